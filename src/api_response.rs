@@ -1,17 +1,41 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use aws_sdk_dynamodb::types::SdkError;
+use crate::request_id::RequestId;
+use crate::telemetry::RequestSpan;
 use rocket::Request;
+use rocket::request::Outcome;
 use rocket::serde::json::Json;
 use rocket::response::Responder;
 use rocket::Response;
-use rocket::http::Status;
+use rocket::http::{Header, Status};
 
 #[derive(rocket::Responder)]
 #[response(status = 200)]
 pub struct ApiResponse<T>(pub Json<T>);
 
+/// An `ApiResponse` for a versioned resource, carrying its version as `ETag`.
+#[derive(rocket::Responder)]
+pub struct ApiVersionedResponse<T> {
+    body: ApiResponse<T>,
+    etag: Header<'static>,
+}
+
+impl<T> ApiVersionedResponse<T> {
+    pub fn new(version: u64, body: T) -> ApiVersionedResponse<T> {
+        ApiVersionedResponse {
+            body: ApiResponse(Json(body)),
+            etag: crate::versioning::etag(version),
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
 struct ApiErrorResponse<'r> {
     message: &'r str,
     requested_path: String,
+    request_id: String,
     code: u8,
     additional_information: &'r str,
 
@@ -19,115 +43,244 @@ struct ApiErrorResponse<'r> {
     http_status: Status,
 }
 
-#[derive(Debug)]
+/// What actually went wrong behind an `ApiError`. Only ever logged, never sent to clients.
+#[derive(Debug, Clone)]
+pub struct ErrorCause {
+    detail: String,
+    source: Option<Arc<dyn Error + Send + Sync>>,
+}
+
+impl ErrorCause {
+    pub fn new(detail: impl Into<String>) -> ErrorCause {
+        ErrorCause {
+            detail: detail.into(),
+            source: None,
+        }
+    }
+
+    pub fn from_source(detail: impl Into<String>, source: impl Error + Send + Sync + 'static) -> ErrorCause {
+        ErrorCause {
+            detail: detail.into(),
+            source: Some(Arc::new(source)),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{}: {}", self.detail, source),
+            None => write!(f, "{}", self.detail),
+        }
+    }
+}
+
+/// Declares every kind of error the API can answer with. Each kind's code is its discriminant, so the compiler
+/// rejects duplicates; codes are part of the public contract and must never be reused or renumbered.
+macro_rules! error_kinds {
+    ($($name:ident = $code:literal, $status:expr, $description:literal;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum ErrorKind {
+            $($name = $code),*
+        }
+
+        impl ErrorKind {
+            pub const ALL: &'static [ErrorKind] = &[$(ErrorKind::$name),*];
+
+            pub fn code(self) -> u8 {
+                self as u8
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(ErrorKind::$name => stringify!($name)),*
+                }
+            }
+
+            pub fn http_status(self) -> Status {
+                match self {
+                    $(ErrorKind::$name => $status),*
+                }
+            }
+
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(ErrorKind::$name => $description),*
+                }
+            }
+        }
+    };
+}
+
+error_kinds! {
+    UserNotFound = 0, Status::NotFound, "The requested user could not be found.";
+    TooManyUsers = 1, Status::InternalServerError, "The login request matched with more than one known entity.";
+    MeNoLikeyAWS = 2, Status::ServiceUnavailable, "A query to an AWS service has failed. Please contact them and express your disgust.";
+    CacheUnavailable = 3, Status::ServiceUnavailable, "Unable to connect or query the server cache (REDIS).";
+    AuthenticationFailed = 4, Status::Unauthorized, "Failed to authenticate entity with the provided credentials.";
+    MissingSessionKey = 5, Status::Unauthorized, "No session key passed as 'nys-session' cookie or bearer token. Please authenticate with GET /v1/public/iam/session";
+    InvalidSession = 6, Status::Unauthorized, "The session key passed appears to be invalid.";
+    NoMatchingPrivilege = 7, Status::Unauthorized, "The AE doesn't have the requisite permission to perform the solicited action on this resource.";
+    MfaAlreadyEnabled = 8, Status::Conflict, "Multi-factor authentication is already enabled for this AE. Disable it before enrolling again.";
+    MfaNotEnrolled = 9, Status::Conflict, "This AE has no pending or active multi-factor enrollment.";
+    InvalidMfaCode = 10, Status::Unauthorized, "The one-time or recovery code is invalid or has already been used.";
+    InvalidMfaChallenge = 11, Status::Unauthorized, "The MFA challenge is unknown, expired or has seen too many attempts. Please log in again.";
+    Internal = 12, Status::InternalServerError, "An unexpected error occurred while processing the request.";
+    MalformedPermission = 13, Status::InternalServerError, "The permission string is malformed.";
+    CsrfTokenMismatch = 14, Status::Forbidden, "State-changing requests authenticated by cookie must repeat the 'nys-csrf' cookie value in the X-CSRF-Token header.";
+    InvalidQueryParameter = 15, Status::BadRequest, "A query parameter is malformed, e.g. an unknown filter value or pagination cursor.";
+    PreconditionFailed = 16, Status::PreconditionFailed, "The resource was modified since it was read. Fetch the current version and retry; If-Match must carry its ETag.";
+    ResourceNotFound = 17, Status::NotFound, "The requested resource does not exist.";
+    AlreadyExists = 18, Status::Conflict, "A resource with this identifier already exists.";
+    InvalidHeader = 19, Status::BadRequest, "A request header is malformed, e.g. an Idempotency-Key that isn't 1-255 printable characters.";
+    IdempotencyKeyReused = 20, Status::UnprocessableEntity, "This Idempotency-Key was already used for a request with a different body.";
+    IdempotencyKeyInProgress = 21, Status::Conflict, "The original request with this Idempotency-Key is still being processed. Retry shortly.";
+    InvalidBatch = 22, Status::BadRequest, "A batch must hold between 1 and 100 operations, each on a different task.";
+    BatchAborted = 23, Status::Conflict, "The operation was rolled back because another operation of the transactional batch failed.";
+    InvalidWebhook = 24, Status::BadRequest, "A webhook needs a permitted receiver URL (absolute, on a public address), a secret of 16-256 characters and at least one event type.";
+    InvalidComment = 25, Status::BadRequest, "A comment must hold between 1 and 4000 characters.";
+    InvalidEntityId = 26, Status::BadRequest, "An AE id must not be empty or contain `*`, `:` or `#`.";
+    TooManyMfaAttempts = 27, Status::TooManyRequests, "Too many one-time or recovery codes were tried for this AE. Wait a few minutes before trying again.";
+}
+
+#[derive(Debug, Clone)]
 pub enum ApiError {
     UserNotFound,
     TooManyUsers,
-    MeNoLikeyAWS,
-    CacheUnavailable,
+    MeNoLikeyAWS(ErrorCause),
+    CacheUnavailable(ErrorCause),
     AuthenticationFailed,
     MissingSessionKey,
     InvalidSession,
     NoMatchingPrivilege,
     MalformedPermission,
+    MfaAlreadyEnabled,
+    MfaNotEnrolled,
+    InvalidMfaCode,
+    InvalidMfaChallenge,
+    Internal(ErrorCause),
+    CsrfTokenMismatch,
+    InvalidQueryParameter,
+    PreconditionFailed,
+    ResourceNotFound,
+    AlreadyExists,
+    InvalidHeader,
+    IdempotencyKeyReused,
+    IdempotencyKeyInProgress,
+    InvalidBatch,
+    InvalidWebhook,
+    InvalidComment,
+    InvalidEntityId,
+    TooManyMfaAttempts,
 }
 
-impl<'r> Responder<'r, 'r> for ApiError {
-    fn respond_to(self, req: &Request) -> rocket::response::Result<'r> {
-        let response_body : ApiErrorResponse;
+impl ApiError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ApiError::UserNotFound => ErrorKind::UserNotFound,
+            ApiError::TooManyUsers => ErrorKind::TooManyUsers,
+            ApiError::MeNoLikeyAWS(_) => ErrorKind::MeNoLikeyAWS,
+            ApiError::CacheUnavailable(_) => ErrorKind::CacheUnavailable,
+            ApiError::AuthenticationFailed => ErrorKind::AuthenticationFailed,
+            ApiError::MissingSessionKey => ErrorKind::MissingSessionKey,
+            ApiError::InvalidSession => ErrorKind::InvalidSession,
+            ApiError::NoMatchingPrivilege => ErrorKind::NoMatchingPrivilege,
+            ApiError::MalformedPermission => ErrorKind::MalformedPermission,
+            ApiError::MfaAlreadyEnabled => ErrorKind::MfaAlreadyEnabled,
+            ApiError::MfaNotEnrolled => ErrorKind::MfaNotEnrolled,
+            ApiError::InvalidMfaCode => ErrorKind::InvalidMfaCode,
+            ApiError::InvalidMfaChallenge => ErrorKind::InvalidMfaChallenge,
+            ApiError::Internal(_) => ErrorKind::Internal,
+            ApiError::CsrfTokenMismatch => ErrorKind::CsrfTokenMismatch,
+            ApiError::InvalidQueryParameter => ErrorKind::InvalidQueryParameter,
+            ApiError::PreconditionFailed => ErrorKind::PreconditionFailed,
+            ApiError::ResourceNotFound => ErrorKind::ResourceNotFound,
+            ApiError::AlreadyExists => ErrorKind::AlreadyExists,
+            ApiError::InvalidHeader => ErrorKind::InvalidHeader,
+            ApiError::IdempotencyKeyReused => ErrorKind::IdempotencyKeyReused,
+            ApiError::IdempotencyKeyInProgress => ErrorKind::IdempotencyKeyInProgress,
+            ApiError::InvalidBatch => ErrorKind::InvalidBatch,
+            ApiError::InvalidWebhook => ErrorKind::InvalidWebhook,
+            ApiError::InvalidComment => ErrorKind::InvalidComment,
+            ApiError::InvalidEntityId => ErrorKind::InvalidEntityId,
+            ApiError::TooManyMfaAttempts => ErrorKind::TooManyMfaAttempts,
+        }
+    }
 
+    pub fn cause(&self) -> Option<&ErrorCause> {
         match self {
-            ApiError::UserNotFound => {
-                response_body = ApiErrorResponse {
-                    message: "UserNotFound",
-                    requested_path: req.uri().to_string(),
-                    code: 0,
-                    additional_information: "The requested user could not be found.",
-
-                    http_status: Status::NotFound,
-                };
-            }
-            ApiError::TooManyUsers => {
-                response_body = ApiErrorResponse {
-                    message: "TooManyUsers",
-                    requested_path: req.uri().to_string(),
-                    code: 1,
-                    additional_information: "The login request matched with more than one known entity.",
-
-                    http_status: Status::InternalServerError,
-                };
-            }
-            ApiError::MeNoLikeyAWS => {
-                response_body = ApiErrorResponse {
-                    message: "MeNoLikeyAWS",
-                    requested_path: req.uri().to_string(),
-                    code: 2,
-                    additional_information: "A query to an AWS service has failed. Please contact them and express your disgust.",
-
-                    http_status: Status::ServiceUnavailable,
-                };
-            }
-            ApiError::CacheUnavailable => {
-                response_body = ApiErrorResponse {
-                    message: "CacheUnavailable",
-                    requested_path: req.uri().to_string(),
-                    code: 3,
-                    additional_information: "Unable to connect or query the server cache (REDIS).",
-
-                    http_status: Status::ServiceUnavailable,
-                };
-            }
-            ApiError::AuthenticationFailed => {
-                response_body = ApiErrorResponse {
-                    message: "AuthenticationFailed",
-                    requested_path: req.uri().to_string(),
-                    code: 4,
-                    additional_information: "Failed to authenticate entity with the provided credentials.",
-
-                    http_status: Status::Unauthorized,
-                };
-            }
-            ApiError::MissingSessionKey => {
-                response_body = ApiErrorResponse {
-                    message: "MissingSessionKey",
-                    requested_path: req.uri().to_string(),
-                    code: 5,
-                    additional_information: "No session key passed as 'nys-session' cookie. Please ensure cookies are enabled and authenticate with GET /v1/public/iam/session",
-
-                    http_status: Status::Unauthorized,
-                };
-            }
-            ApiError::InvalidSession => {
-                response_body = ApiErrorResponse {
-                    message: "InvalidSession",
-                    requested_path: req.uri().to_string(),
-                    code: 6,
-                    additional_information: "The session key passed appears to be invalid.",
-
-                    http_status: Status::Unauthorized,
-                };
-            }
-            ApiError::NoMatchingPrivilege => {
-                response_body = ApiErrorResponse {
-                    message: "NoMatchingPrivilege",
-                    requested_path: req.uri().to_string(),
-                    code: 7,
-                    additional_information: "The AE doesn't have the requisite permission to perform the solicited action on this resource.",
-
-                    http_status: Status::Unauthorized,
-                };
-            }
-            ApiError::MalformedPermission => {
-                response_body = ApiErrorResponse {
-                    message: "MalformedPermission",
-                    requested_path: req.uri().to_string(),
-                    code: 7,
-                    additional_information: "The permission string is malformed.",
-
-                    http_status: Status::InternalServerError,
-                };
-            }
+            ApiError::MeNoLikeyAWS(cause) | ApiError::CacheUnavailable(cause) | ApiError::Internal(cause) => Some(cause),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cause() {
+            Some(cause) => write!(f, "{}", cause),
+            None => write!(f, "{:?}", self),
         }
+    }
+}
+
+impl Error for ApiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause()
+            .and_then(|cause| cause.source.as_ref())
+            .map(|source| source.as_ref() as &(dyn Error + 'static))
+    }
+}
+
+impl<E> From<SdkError<E>> for ApiError where E: Error + Send + Sync + 'static {
+    fn from(err: SdkError<E>) -> Self {
+        ApiError::MeNoLikeyAWS(ErrorCause::from_source("DynamoDB request failed", err))
+    }
+}
+
+impl From<redis::RedisError> for ApiError {
+    fn from(err: redis::RedisError) -> Self {
+        ApiError::CacheUnavailable(ErrorCause::from_source("Redis command failed", err))
+    }
+}
+
+impl From<serde_dynamo::Error> for ApiError {
+    fn from(err: serde_dynamo::Error) -> Self {
+        ApiError::Internal(ErrorCause::from_source("Failed to convert DynamoDB item", err))
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiError::Internal(ErrorCause::from_source("Failed to convert cached JSON", err))
+    }
+}
+
+impl From<bcrypt::BcryptError> for ApiError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        ApiError::Internal(ErrorCause::from_source("Failed to hash credential", err))
+    }
+}
+
+impl<'r> Responder<'r, 'r> for ApiError {
+    fn respond_to(self, req: &Request) -> rocket::response::Result<'r> {
+        // The cause stays in the logs, clients only get the generic description below
+        if let Some(cause) = self.cause() {
+            tracing::error!(parent: &**RequestSpan::of(req), error = %cause, kind = self.kind().name(), "request failed");
+        }
+
+        let kind = self.kind();
+
+        let response_body = ApiErrorResponse {
+            message: kind.name(),
+            requested_path: req.uri().to_string(),
+            request_id: RequestId::of(req).to_string(),
+            code: kind.code(),
+            additional_information: kind.description(),
+
+            http_status: kind.http_status(),
+        };
 
         Response::build_from(Json(&response_body).respond_to(req)?)
             .status(response_body.http_status)
@@ -136,4 +289,44 @@ impl<'r> Responder<'r, 'r> for ApiError {
 }
 
 pub type ApiReturnValue<T> = Result<ApiResponse<T>, ApiError>;
-pub type ApiEmptyReturnValue = Result<(), ApiError>;
\ No newline at end of file
+pub type ApiVersionedReturnValue<T> = Result<ApiVersionedResponse<T>, ApiError>;
+pub type ApiEmptyReturnValue = Result<(), ApiError>;
+
+/// Rocket hands failed guards to a catcher without their error, so the error is parked in the request cache until then.
+struct PendingGuardError(Mutex<Option<ApiError>>);
+
+/// Fails a request guard such that the catcher can still answer with the proper `ApiError` body.
+pub fn guard_failure<S>(req: &Request<'_>, err: ApiError) -> Outcome<S, ApiError> {
+    let pending = req.local_cache(|| PendingGuardError(Mutex::new(None)));
+
+    if let Ok(mut slot) = pending.0.lock() {
+        *slot = Some(err.clone());
+    }
+
+    Outcome::Failure((err.kind().http_status(), err))
+}
+
+#[rocket::catch(default)]
+pub fn guard_failure_catcher(status: Status, req: &Request) -> Result<ApiError, Status> {
+    let pending = req.local_cache(|| PendingGuardError(Mutex::new(None)));
+
+    match pending.0.lock().ok().and_then(|mut slot| slot.take()) {
+        Some(err) => Ok(err),
+        None => Err(status),
+    }
+}
+
+#[cfg(test)]
+mod api_response_tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn error_codes_are_unique() {
+        let codes: HashSet<u8> = ErrorKind::ALL.iter().map(|kind| kind.code()).collect();
+        assert_eq!(codes.len(), ErrorKind::ALL.len());
+
+        let names: HashSet<&str> = ErrorKind::ALL.iter().map(|kind| kind.name()).collect();
+        assert_eq!(names.len(), ErrorKind::ALL.len());
+    }
+}
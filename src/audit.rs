@@ -0,0 +1,262 @@
+use std::sync::OnceLock;
+use aws_sdk_dynamodb::model::AttributeValue;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::api_response::ApiError;
+use crate::db;
+use crate::metrics;
+use crate::public::iam::RequestContext;
+
+/*
+Security-relevant events are recorded here and written to the audit table by a background task, so
+recording never adds DynamoDB latency to (or fails) the request that caused the event. Every event is
+also logged under the `audit` target in case the write itself fails.
+
+Events are keyed by `actor` and a time-ordered `id`, and indexed by `day` for browsing across actors.
+ */
+
+/// Events waiting to be written. When the writer falls this far behind, new events are only logged.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// The largest timestamp that still fits the 13 digit sort key prefix (year 2286).
+const MAX_TIMESTAMP_MILLIS: u64 = 9_999_999_999_999;
+
+static SINK: OnceLock<mpsc::Sender<AuditEvent>> = OnceLock::new();
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    MfaChallengeIssued,
+    MfaLogin,
+    SessionCreated,
+    SessionRevoked,
+    EntityCreated,
+    MfaEnrolled,
+    MfaEnabled,
+    MfaDisabled,
+    PrivilegeCheck,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    Denied,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct AuditEvent {
+    /// Who acted: the session's AE, or the claimed id for logins.
+    actor: String,
+    /// `<unix millis, zero padded>#<uuid>`, so an actor's events sort by time.
+    id: String,
+    /// UTC date (`YYYY-MM-DD`), partition key of the `day-index`.
+    day: String,
+    timestamp: u64,
+    action: AuditAction,
+    outcome: AuditOutcome,
+
+    /// What the action was aimed at, e.g. the privilege that was checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_ip: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, outcome: AuditOutcome, actor: &str, context: &RequestContext) -> AuditEvent {
+        let timestamp = db::now_millis();
+
+        AuditEvent {
+            actor: actor.to_string(),
+            id: format!["{}#{}", sort_prefix(timestamp), Uuid::new_v4()],
+            day: day_of(timestamp),
+            timestamp,
+            action,
+            outcome,
+            target: None,
+            source_ip: context.source_ip.map(|ip| ip.to_string()),
+            request_id: context.request_id.clone(),
+        }
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> AuditEvent {
+        self.target = Some(target.into());
+        self
+    }
+}
+
+fn sort_prefix(timestamp_millis: u64) -> String {
+    format!["{:013}", timestamp_millis]
+}
+
+fn day_of(timestamp_millis: u64) -> String {
+    rocket::time::OffsetDateTime::from_unix_timestamp((timestamp_millis / 1000) as i64)
+        .map(|time| time.date().to_string())
+        .unwrap_or_default()
+}
+
+/// Queues an event for the audit table. Never blocks and never fails the caller.
+pub fn record(event: AuditEvent) {
+    tracing::info!(
+        target: "audit",
+        actor = %event.actor,
+        action = ?event.action,
+        outcome = ?event.outcome,
+        target_resource = event.target.as_deref().unwrap_or(""),
+        request_id = event.request_id.as_deref().unwrap_or(""),
+        "audit event"
+    );
+
+    if let Some(sink) = SINK.get() {
+        if let Err(err) = sink.try_send(event) {
+            tracing::error!(target: "audit", error = %err, "dropped audit event");
+        }
+    }
+}
+
+/// Starts the task that persists recorded events. Without it, events are only logged.
+pub fn spawn_writer(db_client: aws_sdk_dynamodb::Client) {
+    let (sender, mut receiver) = mpsc::channel::<AuditEvent>(QUEUE_CAPACITY);
+
+    if SINK.set(sender).is_err() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            let item = match serde_dynamo::to_item(&event) {
+                Ok(item) => item,
+                Err(err) => {
+                    tracing::error!(target: "audit", error = %err, "failed to convert audit event");
+                    continue;
+                },
+            };
+
+            let put = db_client.put_item()
+                .table_name(db::Table::Audit.as_str())
+                .set_item(Some(item))
+                .send();
+
+            if let Err(err) = metrics::observe("dynamodb", "PutItem", put).await {
+                tracing::error!(target: "audit", error = %err, actor = %event.actor, id = %event.id, "failed to persist audit event");
+            }
+        }
+    });
+}
+
+/// Filters for `query`. Without an actor, events are browsed by day, defaulting to the day of `to` (or today).
+#[derive(Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub day: Option<String>,
+    pub action: Option<AuditAction>,
+    pub outcome: Option<AuditOutcome>,
+    /// Unix seconds, inclusive.
+    pub from: Option<u64>,
+    /// Unix seconds, inclusive.
+    pub to: Option<u64>,
+    pub limit: i32,
+    pub cursor: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct AuditPage {
+    events: Vec<AuditEvent>,
+    /// Pass back as `cursor` for the next page; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+/// Parses a `snake_case` action or outcome name as used in the stored events.
+pub fn parse_label<T: serde::de::DeserializeOwned>(label: &str) -> Option<T> {
+    serde_json::from_value(serde_json::Value::String(label.to_string())).ok()
+}
+
+/// Newest events first.
+pub async fn query(db_client: &aws_sdk_dynamodb::Client, query: AuditQuery) -> Result<AuditPage, ApiError> {
+    let mut request = db_client.query()
+        .table_name(db::Table::Audit.as_str())
+        .scan_index_forward(false)
+        .limit(query.limit);
+
+    let partition = match &query.actor {
+        Some(actor) => {
+            request = request.key_condition_expression("actor = :partition AND id BETWEEN :from AND :to");
+            actor.clone()
+        },
+        None => {
+            request = request.index_name("day-index").key_condition_expression("#day = :partition AND id BETWEEN :from AND :to")
+                .expression_attribute_names("#day", "day");
+            query.day.clone().unwrap_or_else(|| day_of(query.to.map_or_else(db::now_millis, |to| to.saturating_mul(1000).min(MAX_TIMESTAMP_MILLIS))))
+        },
+    };
+
+    // '~' sorts after '#' and every uuid character, making `to` inclusive
+    let from = sort_prefix(query.from.unwrap_or(0).saturating_mul(1000).min(MAX_TIMESTAMP_MILLIS));
+    let to = format!["{}~", sort_prefix(query.to.map_or(MAX_TIMESTAMP_MILLIS, |to| to.saturating_mul(1000).saturating_add(999).min(MAX_TIMESTAMP_MILLIS)))];
+
+    request = request
+        .expression_attribute_values(":partition", AttributeValue::S(partition))
+        .expression_attribute_values(":from", AttributeValue::S(from))
+        .expression_attribute_values(":to", AttributeValue::S(to));
+
+    let mut filters = Vec::new();
+
+    if let Some(action) = query.action {
+        filters.push("#action = :action");
+        request = request.expression_attribute_names("#action", "action")
+            .expression_attribute_values(":action", AttributeValue::S(label_of(&action)?));
+    }
+
+    if let Some(outcome) = query.outcome {
+        filters.push("#outcome = :outcome");
+        request = request.expression_attribute_names("#outcome", "outcome")
+            .expression_attribute_values(":outcome", AttributeValue::S(label_of(&outcome)?));
+    }
+
+    if !filters.is_empty() {
+        request = request.filter_expression(filters.join(" AND "));
+    }
+
+    if let Some(cursor) = &query.cursor {
+        request = request.set_exclusive_start_key(Some(db::decode_cursor(cursor).ok_or(ApiError::InvalidQueryParameter)?));
+    }
+
+    let result = metrics::observe("dynamodb", "Query", request.send()).await?;
+
+    Ok(AuditPage {
+        events: serde_dynamo::from_items(result.items.unwrap_or_default())?,
+        next_cursor: result.last_evaluated_key.as_ref().map(db::encode_cursor),
+    })
+}
+
+fn label_of<T: serde::Serialize>(label: &T) -> Result<String, ApiError> {
+    match serde_json::to_value(label)? {
+        serde_json::Value::String(label) => Ok(label),
+        _ => Err(ApiError::InvalidQueryParameter),
+    }
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use super::*;
+
+    #[test]
+    fn events_sort_by_time() {
+        let context = RequestContext::default();
+        let earlier = AuditEvent::new(AuditAction::Login, AuditOutcome::Success, "nate", &context);
+        let later = AuditEvent { timestamp: earlier.timestamp + 1, ..earlier.clone() };
+
+        assert!(sort_prefix(earlier.timestamp) < sort_prefix(later.timestamp));
+        assert_eq!(day_of(0), "1970-01-01");
+        assert_eq!(parse_label::<AuditAction>("mfa_login"), Some(AuditAction::MfaLogin));
+        assert_eq!(parse_label::<AuditOutcome>("maybe"), None);
+    }
+}
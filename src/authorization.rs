@@ -0,0 +1,353 @@
+use std::any::TypeId;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use rocket::{Request, Route};
+use rocket::http::Method;
+use rocket::route::StaticInfo;
+use rocket::request::{FromRequest, Outcome};
+use crate::api_response::{guard_failure, ApiError};
+use crate::public::iam::{is_valid_entity_id, AuthenticatableEntity, SessionKey};
+
+/// A permission a route requires. `{name}` placeholders in the template are filled from the route parameter of the same name,
+/// `{self}` with the id of the session's AE.
+pub trait Permission: Send + Sync + 'static {
+    const TEMPLATE: &'static str;
+}
+
+macro_rules! permissions {
+    ($($(#[$meta:meta])* $name:ident => $template:literal;)*) => {
+        $(
+            $(#[$meta])*
+            pub struct $name;
+
+            impl Permission for $name {
+                const TEMPLATE: &'static str = $template;
+            }
+        )*
+
+        /// The template behind each `Authorized<P>` guard type.
+        fn authorized_templates() -> Vec<(TypeId, &'static str)> {
+            vec![$((TypeId::of::<Authorized<$name>>(), $name::TEMPLATE)),*]
+        }
+    };
+}
+
+permissions! {
+    TaskListRead => "nys:tasker:{entity}:TaskList:Read";
+    TaskListWrite => "nys:tasker:{entity}:TaskList:Write";
+    AuditLogRead => "nys:audit:*:AuditLog:Read";
+    WebhookRead => "nys:tasker:{entity}:Webhook:Read";
+    WebhookWrite => "nys:tasker:{entity}:Webhook:Write";
+    AuthenticatableEntityWrite => "nys:iam:{self}:AuthenticatableEntity:Write";
+    MetricsRead => "nys:metrics:*:Metrics:Read";
+}
+
+/// The template behind each guard type that checks a permission.
+fn guard_templates() -> Vec<(TypeId, &'static str)> {
+    let mut templates = authorized_templates();
+
+    // Allowlisted scrapers get in without a session, everyone else needs the permission
+    templates.push((TypeId::of::<crate::metrics::MetricsAccess>(), MetricsRead::TEMPLATE));
+
+    templates
+}
+
+/// Request guard that only succeeds if the session's AE holds `P` for the requested resource.
+pub struct Authorized<P: Permission> {
+    ae: AuthenticatableEntity,
+    /// `P` rendered for the requested resource.
+    privilege: String,
+    _permission: PhantomData<P>,
+}
+
+impl<P: Permission> Authorized<P> {
+    /// Repeats the check against the session and the AE's current grants, see `AuthenticatableEntity::reauthorize`.
+    pub async fn reauthorize(&self, db_client: &aws_sdk_dynamodb::Client, redis_client: &redis::Client, session_key: &SessionKey) -> Result<(), ApiError> {
+        self.ae.reauthorize(db_client, redis_client, session_key, &self.privilege).await
+    }
+}
+
+impl<P: Permission> Deref for Authorized<P> {
+    type Target = AuthenticatableEntity;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ae
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, P: Permission> FromRequest<'r> for Authorized<P> {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ae = match req.guard::<AuthenticatableEntity>().await {
+            Outcome::Success(ae) => ae,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+
+        let privilege = match render_permission(P::TEMPLATE, req, &ae.id) {
+            Ok(privilege) => privilege,
+            Err(err) => return guard_failure(req, err),
+        };
+
+        match ae.assert_privilege(privilege.clone()) {
+            Ok(()) => Outcome::Success(Authorized { ae, privilege, _permission: PhantomData }),
+            Err(err) => guard_failure(req, err),
+        }
+    }
+}
+
+/// Substitutes the route parameters of the matched route, and the session AE's id, into a permission template.
+/// Template placeholders name entities, so a parameter that isn't a valid entity id is the client's mistake.
+fn render_permission(template: &str, req: &Request<'_>, ae_id: &str) -> Result<String, ApiError> {
+    let route = req.route().ok_or(ApiError::MalformedPermission)?;
+    let route_segments: Vec<&str> = route.uri.unmounted_origin.path().as_str()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        let close = open + rest[open..].find('}').ok_or(ApiError::MalformedPermission)?;
+        let name = &rest[open + 1..close];
+
+        rendered.push_str(&rest[..open]);
+        rest = &rest[close + 1..];
+
+        if name == "self" {
+            rendered.push_str(ae_id);
+            continue;
+        }
+
+        let value = route_segments.iter()
+            .position(|segment| *segment == format!["<{}>", name])
+            .and_then(|index| req.routed_segment(index))
+            .ok_or(ApiError::MalformedPermission)?;
+
+        // A parameter must not be able to smuggle in extra permission components
+        if !is_valid_entity_id(value) {
+            return Err(ApiError::InvalidEntityId);
+        }
+
+        rendered.push_str(value);
+    }
+
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// What a route demands through its guards.
+pub struct RouteRequirement {
+    route: &'static str,
+    method: Method,
+    /// The route's path before mounting, the same handler name may be used by several modules.
+    uri: String,
+    /// Whether the route needs a session, either directly or through an `Authorized<P>`.
+    authenticated: bool,
+    permissions: Vec<&'static str>,
+}
+
+/// Reads the requirements of a route off its guard types. Only called through `guarded_routes!`.
+pub fn requirements_of(info: StaticInfo) -> RouteRequirement {
+    let templates = guard_templates();
+    let session_guards: Vec<TypeId> = authorized_templates().into_iter()
+        .map(|(guard, _)| guard)
+        .chain(std::iter::once(TypeId::of::<AuthenticatableEntity>()))
+        .collect();
+
+    // A guard nested in another one, e.g. an `Option`, doesn't demand anything
+    let guards: Vec<TypeId> = info.sentinels.iter()
+        .filter(|sentry| sentry.parent.is_none())
+        .map(|sentry| sentry.type_id)
+        .collect();
+
+    let name = info.name;
+    let route = Route::from(info);
+
+    RouteRequirement {
+        route: name,
+        method: route.method,
+        uri: route.uri.unmounted_origin.to_string(),
+        authenticated: guards.iter().any(|guard| session_guards.contains(guard)),
+        permissions: guards.iter()
+            .filter_map(|guard| templates.iter().find(|(template_guard, _)| template_guard == guard).map(|(_, template)| *template))
+            .collect(),
+    }
+}
+
+/// Defines a module's `routes()`, and its `requirements()` as read off the routes' guards.
+macro_rules! guarded_routes {
+    ($($route:ident),* $(,)?) => {
+        pub fn routes() -> Vec<rocket::Route> {
+            rocket::routes![$($route),*]
+        }
+
+        pub fn requirements() -> Vec<$crate::authorization::RouteRequirement> {
+            vec![$($crate::authorization::requirements_of($route {}.into_info())),*]
+        }
+    };
+}
+
+pub(crate) use guarded_routes;
+
+impl RouteRequirement {
+    fn matches(&self, route: &Route) -> bool {
+        route.name.as_deref() == Some(self.route) && route.method == self.method && route.uri.unmounted_origin.to_string() == self.uri
+    }
+}
+
+/// The requirements of every route set `main` mounts, in one place for enumeration.
+fn declared_requirements() -> Vec<RouteRequirement> {
+    [
+        crate::requirements(),
+        crate::metrics::requirements(),
+        crate::cors::requirements(),
+        crate::health::requirements(),
+        crate::private::tasker::requirements(),
+        crate::private::webhooks::requirements(),
+        crate::private::task_history::requirements(),
+        crate::private::debug::requirements(),
+        crate::private::mfa::requirements(),
+        crate::private::audit::requirements(),
+        crate::public::iam::requirements(),
+        crate::public::info::requirements(),
+    ].into_iter().flatten().collect()
+}
+
+#[derive(serde::Serialize)]
+pub struct RoutePermissions {
+    name: Option<String>,
+    method: String,
+    path: String,
+    authenticated: bool,
+    permissions: Vec<&'static str>,
+}
+
+/// Lists whether each route needs a session and the permission templates it demands through its guards.
+pub fn route_permissions<'a>(routes: impl Iterator<Item = &'a Route>) -> Vec<RoutePermissions> {
+    let declared = declared_requirements();
+
+    routes
+        .map(|route| {
+            let requirement = declared.iter().find(|requirement| requirement.matches(route));
+
+            RoutePermissions {
+                name: route.name.as_ref().map(|name| name.to_string()),
+                method: route.method.to_string(),
+                path: route.uri.to_string(),
+                authenticated: requirement.is_some_and(|requirement| requirement.authenticated),
+                permissions: requirement.map(|requirement| requirement.permissions.clone()).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// Request guard exposing the permission catalog of every route mounted on this instance.
+pub struct RouteCatalog(pub Vec<RoutePermissions>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RouteCatalog {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RouteCatalog(route_permissions(req.rocket().routes())))
+    }
+}
+
+#[cfg(test)]
+mod authorization_tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    /// Renders `TaskListRead` for whatever request reaches it, standing in for the session part of `Authorized`.
+    struct Rendered(Result<String, ApiError>);
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for Rendered {
+        type Error = ();
+
+        async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+            Outcome::Success(Rendered(render_permission(TaskListRead::TEMPLATE, req, "me")))
+        }
+    }
+
+    #[rocket::get("/<entity>/task")]
+    fn probe(entity: &str, rendered: Rendered) -> String {
+        match rendered.0 {
+            Ok(privilege) => privilege,
+            Err(err) => format!["{} {:?}", entity, err.kind()],
+        }
+    }
+
+    #[test]
+    fn route_parameters_cannot_smuggle_permission_components() {
+        let client = Client::untracked(rocket::build().mount("/", rocket::routes![probe])).unwrap();
+
+        assert_eq!(client.get("/alice/task").dispatch().into_string().unwrap(), "nys:tasker:alice:TaskList:Read");
+        assert_eq!(client.get("/alice:TaskList:Read:x/task").dispatch().into_string().unwrap(), "alice:TaskList:Read:x InvalidEntityId");
+    }
+
+    #[test]
+    fn tasker_routes_declare_permissions() {
+        let catalog = route_permissions(crate::private::tasker::routes().iter());
+
+        let create_task = catalog.iter().find(|route| route.name.as_deref() == Some("create_task")).unwrap();
+        assert_eq!(create_task.permissions, vec![TaskListWrite::TEMPLATE]);
+
+        let get_all_tasks = catalog.iter().find(|route| route.name.as_deref() == Some("get_all_tasks")).unwrap();
+        assert_eq!(get_all_tasks.permissions, vec![TaskListRead::TEMPLATE]);
+    }
+
+    #[test]
+    fn every_guarded_route_is_in_the_catalog() {
+        let routes: Vec<Route> = crate::private::tasker::routes().into_iter()
+            .chain(crate::private::webhooks::routes())
+            .chain(crate::private::task_history::routes())
+            .chain(crate::private::audit::routes())
+            .chain(crate::private::mfa::routes())
+            .collect();
+
+        for route in route_permissions(routes.iter()) {
+            assert_eq!(route.permissions.len(), 1, "{:?} {} declares {:?}", route.name, route.path, route.permissions);
+        }
+    }
+
+    #[test]
+    fn every_mounted_route_is_declared() {
+        let declared = declared_requirements();
+
+        for route in crate::mount(rocket::build()).routes() {
+            let declarations = declared.iter().filter(|requirement| requirement.matches(route)).count();
+            assert_eq!(declarations, 1, "{:?} {} {} isn't declared once, mount it through `guarded_routes!`", route.name, route.method, route.uri);
+        }
+    }
+
+    #[test]
+    fn session_and_permission_guards_are_read_off_the_handlers() {
+        let catalog = route_permissions(crate::private::debug::routes().iter()
+            .chain(crate::metrics::routes().iter())
+            .chain(crate::health::routes().iter()));
+
+        let whoami = catalog.iter().find(|route| route.name.as_deref() == Some("whoami")).unwrap();
+        assert!(whoami.authenticated && whoami.permissions.is_empty());
+
+        let metrics = catalog.iter().find(|route| route.name.as_deref() == Some("metrics")).unwrap();
+        assert!(!metrics.authenticated);
+        assert_eq!(metrics.permissions, vec![MetricsRead::TEMPLATE]);
+
+        let health = catalog.iter().find(|route| route.name.as_deref() == Some("health")).unwrap();
+        assert!(!health.authenticated && health.permissions.is_empty());
+    }
+
+    #[test]
+    fn same_named_handlers_do_not_share_requirements() {
+        let mut impostor = crate::private::webhooks::routes().into_iter().find(|route| route.name.as_deref() == Some("create_webhook")).unwrap();
+        impostor.name = Some("create_task".into());
+
+        assert!(route_permissions(std::iter::once(&impostor))[0].permissions.is_empty());
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt;
+use rocket::figment::Figment;
+use rocket::figment::providers::Env;
+use rocket::http::SameSite;
+use serde::de::DeserializeOwned;
+use crate::db::{self, Table};
+
+/*
+Settings come from Rocket.toml, `ROCKET_` env vars, then `NYS_` env vars (highest priority).
+Nested keys use a double underscore in the environment, e.g. `NYS_AWS__REGION` for `aws.region`.
+ */
+
+/// Credentials and region fall back to the default AWS provider chain (env, profile, web identity, IMDS) when unset.
+pub struct AwsConfig {
+    pub access_id: Option<String>,
+    pub secret: Option<String>,
+    pub region: Option<String>,
+    /// Points DynamoDB somewhere other than AWS, e.g. `http://localhost:8000` for DynamoDB Local.
+    pub dynamodb_endpoint: Option<String>,
+}
+
+pub struct TablesConfig {
+    /// Prepended to every table name so several environments can share an AWS account, e.g. `staging_`.
+    pub prefix: String,
+    pub iam: String,
+    pub tasker: String,
+    pub meta: String,
+    pub audit: String,
+    pub webhooks: String,
+    pub webhook_deliveries: String,
+}
+
+impl Default for TablesConfig {
+    fn default() -> Self {
+        TablesConfig {
+            prefix: String::new(),
+            iam: "NYS_iam".to_string(),
+            tasker: "NYS_tasker".to_string(),
+            meta: "NYS_meta".to_string(),
+            audit: "NYS_audit".to_string(),
+            webhooks: "NYS_webhooks".to_string(),
+            webhook_deliveries: "NYS_webhook_deliveries".to_string(),
+        }
+    }
+}
+
+pub struct CookieConfig {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    pub path: String,
+}
+
+pub struct SessionConfig {
+    pub ttl_seconds: usize,
+    pub mfa_challenge_ttl_seconds: usize,
+}
+
+/// Short secrets would let a leaked fingerprint be brute-forced back into the secret.
+const MIN_FINGERPRINT_SECRET_LENGTH: usize = 32;
+
+pub struct IdempotencyConfig {
+    /// How long a completed request is remembered for replay.
+    pub window_seconds: usize,
+    /// Keys the request body fingerprints, shared by every instance so retries may land on any of them.
+    pub fingerprint_secret: String,
+}
+
+/// Failed deliveries are retried after `initial_backoff_seconds`, doubling up to `max_backoff_seconds`.
+pub struct WebhooksConfig {
+    /// Attempts per delivery, including the first.
+    pub max_attempts: u32,
+    pub initial_backoff_seconds: u64,
+    pub max_backoff_seconds: u64,
+    pub timeout_seconds: u64,
+    /// Accept plain `http://` URLs, e.g. for a local receiver in development.
+    pub allow_http: bool,
+}
+
+/// Origins are either `*` or exact `scheme://host[:port]` values.
+pub struct CorsConfig {
+    /// Applies to `/v1/public` and everything outside `/v1/private`.
+    pub public_allowed_origins: Vec<String>,
+    pub private_allowed_origins: Vec<String>,
+    pub max_age_seconds: u32,
+}
+
+pub struct SecurityHeadersConfig {
+    /// 0 leaves `Strict-Transport-Security` out, e.g. for plain HTTP in development.
+    pub hsts_max_age_seconds: u64,
+    pub frame_options: String,
+    pub referrer_policy: String,
+    pub content_security_policy: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+pub struct AppConfig {
+    pub aws: AwsConfig,
+    pub redis_url: String,
+    pub tables: TablesConfig,
+    pub cookie: CookieConfig,
+    pub session: SessionConfig,
+    pub idempotency: IdempotencyConfig,
+    pub webhooks: WebhooksConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub metrics_allowed_sources: Vec<String>,
+    /// Reverse proxies whose `X-Real-IP` header is believed, as CIDR ranges.
+    pub trusted_proxies: Vec<String>,
+    pub log_format: LogFormat,
+}
+
+/// Every problem found while loading the configuration, so they can all be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads keys one by one, recording failures instead of stopping at the first.
+struct ConfigReader<'a> {
+    figment: &'a Figment,
+    problems: Vec<String>,
+}
+
+impl<'a> ConfigReader<'a> {
+    fn required<T: DeserializeOwned + Default>(&mut self, key: &str) -> T {
+        if !self.figment.contains(key) {
+            self.problems.push(format!["`{}` is missing (env: {})", key, env_name(key)]);
+            return T::default();
+        }
+
+        self.optional(key, T::default())
+    }
+
+    fn optional<T: DeserializeOwned>(&mut self, key: &str, default: T) -> T {
+        if !self.figment.contains(key) {
+            return default;
+        }
+
+        match self.figment.extract_inner(key) {
+            Ok(value) => value,
+            Err(err) => {
+                self.problems.push(format!["`{}` is invalid: {}", key, err]);
+                default
+            },
+        }
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!["NYS_{}", key.replace('.', "__").to_uppercase()]
+}
+
+fn is_valid_origin(origin: &str) -> bool {
+    if origin == "*" {
+        return true;
+    }
+
+    match origin.split_once("://") {
+        Some((scheme, authority)) => !scheme.is_empty() && !authority.is_empty() && !authority.contains('/'),
+        None => false,
+    }
+}
+
+impl AppConfig {
+    /// The figment shared by Rocket and the application config.
+    pub fn figment() -> Figment {
+        // The variables used before typed configuration existed keep working
+        let legacy_env = Env::raw()
+            .only(&["AWS_ACCESS_ID", "AWS_SECRET", "AWS_REGION", "REDIS"])
+            .map(|key| match key.as_str().to_uppercase().as_str() {
+                "AWS_ACCESS_ID" => "aws.access_id".into(),
+                "AWS_SECRET" => "aws.secret".into(),
+                "AWS_REGION" => "aws.region".into(),
+                _ => "redis.url".into(),
+            });
+
+        rocket::Config::figment()
+            .merge(legacy_env)
+            .merge(Env::prefixed("NYS_").split("__"))
+    }
+
+    pub fn load(figment: &Figment) -> Result<AppConfig, ConfigError> {
+        let mut reader = ConfigReader { figment, problems: Vec::new() };
+
+        let log_format: String = reader.optional("log_format", "text".to_string());
+        let log_format = match log_format.as_str() {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            other => {
+                reader.problems.push(format!["`log_format` is invalid: expected `text` or `json`, found `{}`", other]);
+                LogFormat::Text
+            },
+        };
+
+        let same_site: String = reader.optional("cookie.same_site", "strict".to_string());
+        let same_site = match same_site.to_ascii_lowercase().as_str() {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            other => {
+                reader.problems.push(format!["`cookie.same_site` is invalid: expected `strict`, `lax` or `none`, found `{}`", other]);
+                SameSite::Strict
+            },
+        };
+
+        let default_tables = TablesConfig::default();
+
+        let config = AppConfig {
+            aws: AwsConfig {
+                access_id: reader.optional("aws.access_id", None),
+                secret: reader.optional("aws.secret", None),
+                region: reader.optional("aws.region", None),
+                dynamodb_endpoint: reader.optional("aws.dynamodb_endpoint", None),
+            },
+            redis_url: reader.required("redis.url"),
+            tables: TablesConfig {
+                prefix: reader.optional("tables.prefix", default_tables.prefix),
+                iam: reader.optional("tables.iam", default_tables.iam),
+                tasker: reader.optional("tables.tasker", default_tables.tasker),
+                meta: reader.optional("tables.meta", default_tables.meta),
+                audit: reader.optional("tables.audit", default_tables.audit),
+                webhooks: reader.optional("tables.webhooks", default_tables.webhooks),
+                webhook_deliveries: reader.optional("tables.webhook_deliveries", default_tables.webhook_deliveries),
+            },
+            cookie: CookieConfig {
+                secure: reader.optional("cookie.secure", true),
+                same_site,
+                domain: reader.optional("cookie.domain", None),
+                path: reader.optional("cookie.path", "/v1".to_string()),
+            },
+            session: SessionConfig {
+                ttl_seconds: reader.optional("session.ttl_seconds", 60 * 60 * 24 * 7),
+                mfa_challenge_ttl_seconds: reader.optional("session.mfa_challenge_ttl_seconds", 300),
+            },
+            idempotency: IdempotencyConfig {
+                window_seconds: reader.optional("idempotency.window_seconds", 60 * 60 * 24),
+                fingerprint_secret: reader.required("idempotency.fingerprint_secret"),
+            },
+            webhooks: WebhooksConfig {
+                max_attempts: reader.optional("webhooks.max_attempts", 6),
+                initial_backoff_seconds: reader.optional("webhooks.initial_backoff_seconds", 10),
+                max_backoff_seconds: reader.optional("webhooks.max_backoff_seconds", 60 * 60),
+                timeout_seconds: reader.optional("webhooks.timeout_seconds", 10),
+                allow_http: reader.optional("webhooks.allow_http", false),
+            },
+            cors: CorsConfig {
+                public_allowed_origins: reader.optional("cors.public.allowed_origins", vec!["*".to_string()]),
+                private_allowed_origins: reader.optional("cors.private.allowed_origins", Vec::new()),
+                max_age_seconds: reader.optional("cors.max_age_seconds", 600),
+            },
+            security_headers: SecurityHeadersConfig {
+                hsts_max_age_seconds: reader.optional("security_headers.hsts_max_age_seconds", 60 * 60 * 24 * 365),
+                frame_options: reader.optional("security_headers.frame_options", "DENY".to_string()),
+                referrer_policy: reader.optional("security_headers.referrer_policy", "no-referrer".to_string()),
+                content_security_policy: reader.optional("security_headers.content_security_policy", "default-src 'none'; frame-ancestors 'none'".to_string()),
+            },
+            metrics_allowed_sources: reader.optional("metrics.allowed_sources", vec!["127.0.0.1/32".to_string(), "::1/128".to_string()]),
+            trusted_proxies: reader.optional("trusted_proxies", Vec::new()),
+            log_format,
+        };
+
+        if config.aws.access_id.is_some() != config.aws.secret.is_some() {
+            reader.problems.push("`aws.access_id` and `aws.secret` must be set together, or both left unset to use the default credential chain".to_string());
+        }
+
+        if let Some(endpoint) = &config.aws.dynamodb_endpoint {
+            if rocket::http::uri::Absolute::parse(endpoint).is_err() {
+                reader.problems.push(format!["`aws.dynamodb_endpoint` is invalid: expected an absolute URI, found `{}`", endpoint]);
+            }
+        }
+
+        for table in Table::ALL {
+            let name = table.resolve(&config.tables);
+
+            if !db::is_valid_table_name(&name) {
+                reader.problems.push(format!["table name `{}` is invalid: DynamoDB expects 3-255 characters out of `A-Z a-z 0-9 _ . -`", name]);
+            }
+        }
+
+        if config.cookie.same_site == SameSite::None && !config.cookie.secure {
+            reader.problems.push("`cookie.same_site = \"none\"` requires `cookie.secure = true`, browsers drop the cookie otherwise".to_string());
+        }
+
+        if config.idempotency.window_seconds == 0 {
+            reader.problems.push("`idempotency.window_seconds` must be greater than zero".to_string());
+        }
+
+        if !config.idempotency.fingerprint_secret.is_empty() && config.idempotency.fingerprint_secret.len() < MIN_FINGERPRINT_SECRET_LENGTH {
+            reader.problems.push(format!["`idempotency.fingerprint_secret` must be at least {} characters", MIN_FINGERPRINT_SECRET_LENGTH]);
+        }
+
+        if config.webhooks.max_attempts == 0 {
+            reader.problems.push("`webhooks.max_attempts` must be greater than zero".to_string());
+        }
+
+        if config.webhooks.timeout_seconds == 0 {
+            reader.problems.push("`webhooks.timeout_seconds` must be greater than zero".to_string());
+        }
+
+        for origin in config.cors.public_allowed_origins.iter().chain(&config.cors.private_allowed_origins) {
+            if !is_valid_origin(origin) {
+                reader.problems.push(format!["CORS origin `{}` is invalid: expected `*` or `scheme://host[:port]`", origin]);
+            }
+        }
+
+        if config.session.ttl_seconds == 0 {
+            reader.problems.push("`session.ttl_seconds` must be greater than zero".to_string());
+        }
+
+        if config.session.mfa_challenge_ttl_seconds == 0 {
+            reader.problems.push("`session.mfa_challenge_ttl_seconds` must be greater than zero".to_string());
+        }
+
+        match reader.problems.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError(reader.problems)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use rocket::figment::providers::Serialized;
+
+    #[test]
+    fn reports_every_missing_key() {
+        let figment = Figment::from(Serialized::default("aws.access_id", "id"));
+        let message = AppConfig::load(&figment).err().unwrap().to_string();
+
+        assert!(message.contains("`redis.url` is missing (env: NYS_REDIS__URL)"));
+        assert!(message.contains("`idempotency.fingerprint_secret` is missing (env: NYS_IDEMPOTENCY__FINGERPRINT_SECRET)"));
+        assert!(message.contains("`aws.access_id` and `aws.secret` must be set together"));
+    }
+
+    #[test]
+    fn aws_settings_are_optional() {
+        let figment = Figment::from(Serialized::default("redis.url", "redis://localhost"))
+            .merge(Serialized::default("idempotency.fingerprint_secret", "0123456789abcdef0123456789abcdef"));
+        let config = AppConfig::load(&figment).ok().unwrap();
+
+        assert!(config.aws.access_id.is_none());
+        assert!(config.aws.dynamodb_endpoint.is_none());
+    }
+
+    #[test]
+    fn reports_invalid_values() {
+        let figment = Figment::new()
+            .merge(Serialized::default("aws", serde_json::json!({ "access_id": "id", "secret": "secret", "region": "eu-central-1" })))
+            .merge(Serialized::default("redis.url", "redis://localhost"))
+            .merge(Serialized::default("aws.dynamodb_endpoint", "localhost 8000"))
+            .merge(Serialized::default("session.ttl_seconds", "forever"))
+            .merge(Serialized::default("idempotency.fingerprint_secret", "hunter2"))
+            .merge(Serialized::default("tables.prefix", "staging/"))
+            .merge(Serialized::default("cors.private.allowed_origins", ["https://app.example.com/"]))
+            .merge(Serialized::default("log_format", "xml"));
+
+        let message = AppConfig::load(&figment).err().unwrap().to_string();
+
+        assert!(message.contains("`session.ttl_seconds` is invalid"));
+        assert!(message.contains("`log_format` is invalid"));
+        assert!(message.contains("`idempotency.fingerprint_secret` must be at least 32 characters"));
+        assert!(message.contains("`aws.dynamodb_endpoint` is invalid"));
+        assert!(message.contains("table name `staging/NYS_iam` is invalid"));
+        assert!(message.contains("CORS origin `https://app.example.com/` is invalid"));
+    }
+}
@@ -1,11 +1,59 @@
 use rocket::{Request, Response};
-use rocket::http::Header;
+use rocket::http::{Header, Method, Status};
 use rocket::fairing::{Fairing, Info, Kind};
+use crate::authorization::guarded_routes;
+use crate::mounts::PerMount;
 
-pub struct CORS;
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+const DEFAULT_ALLOWED_HEADERS: &str = "Content-Type, Authorization, X-Request-Id, X-CSRF-Token, Idempotency-Key, If-Match";
+
+pub struct CorsPolicy {
+    allowed_origins: Vec<String>,
+    max_age_seconds: u32,
+}
+
+enum AllowedOrigin<'a> {
+    /// `*`, browsers refuse to send credentials to it.
+    Any,
+    /// The request origin reflected back, which is what credentialed requests need.
+    Exact(&'a str),
+}
+
+impl CorsPolicy {
+    pub fn new(allowed_origins: Vec<String>, max_age_seconds: u32) -> CorsPolicy {
+        CorsPolicy { allowed_origins, max_age_seconds }
+    }
+
+    fn allow<'a>(&self, origin: &'a str) -> Option<AllowedOrigin<'a>> {
+        // An exact entry wins over the wildcard so configured origins still get credentials
+        if self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            Some(AllowedOrigin::Exact(origin))
+        } else if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some(AllowedOrigin::Any)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies the CORS policy of the mount a request falls under, answering preflights through `preflight`.
+pub struct Cors {
+    policies: PerMount<CorsPolicy>,
+}
+
+impl Cors {
+    /// `fallback` applies to every path not covered by a more specific `mount`.
+    pub fn new(fallback: CorsPolicy) -> Cors {
+        Cors { policies: PerMount::new(fallback) }
+    }
+
+    pub fn mount(self, base: &str, policy: CorsPolicy) -> Cors {
+        Cors { policies: self.policies.mount(base, policy) }
+    }
+}
 
 #[rocket::async_trait]
-impl Fairing for CORS {
+impl Fairing for Cors {
     fn info(&self) -> Info {
         Info {
             name: "CORS",
@@ -13,10 +61,64 @@ impl Fairing for CORS {
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, PATCH, OPTIONS"));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // Caches must not hand one origin's answer to another
+        response.adjoin_header(Header::new("Vary", "Origin"));
+
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) => origin,
+            None => return,
+        };
+
+        let policy = self.policies.get(request.uri().path().as_str());
+
+        match policy.allow(origin) {
+            Some(AllowedOrigin::Any) => {
+                response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+            },
+            Some(AllowedOrigin::Exact(origin)) => {
+                response.set_header(Header::new("Access-Control-Allow-Origin", origin.to_string()));
+                response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+            },
+            None => return,
+        }
+
+        response.set_header(Header::new("Access-Control-Expose-Headers", crate::request_id::REQUEST_ID_HEADER));
+
+        if request.method() == Method::Options {
+            let allowed_headers = request.headers().get_one("Access-Control-Request-Headers").unwrap_or(DEFAULT_ALLOWED_HEADERS);
+
+            response.set_header(Header::new("Access-Control-Allow-Methods", ALLOWED_METHODS));
+            response.set_header(Header::new("Access-Control-Allow-Headers", allowed_headers.to_string()));
+            response.set_header(Header::new("Access-Control-Max-Age", policy.max_age_seconds.to_string()));
+        }
+    }
+}
+
+/// Answers every preflight, the fairing decides which origins get the CORS headers.
+#[rocket::options("/<_..>")]
+pub fn preflight() -> Status {
+    Status::NoContent
+}
+
+guarded_routes![preflight];
+
+#[cfg(test)]
+mod cors_tests {
+    use super::*;
+
+    fn cors() -> Cors {
+        Cors::new(CorsPolicy::new(vec!["*".to_string()], 600))
+            .mount("/v1/private", CorsPolicy::new(vec!["https://app.example.com".to_string()], 600))
+    }
+
+    #[test]
+    fn origins_follow_the_mount_policy() {
+        let cors = cors();
+
+        assert!(matches!(cors.policies.get("/v1/private/tasker/me/task").allow("https://app.example.com"), Some(AllowedOrigin::Exact(_))));
+        assert!(cors.policies.get("/v1/private").allow("https://evil.example.com").is_none());
+        assert!(matches!(cors.policies.get("/v1/privateer").allow("https://evil.example.com"), Some(AllowedOrigin::Any)));
+        assert!(matches!(cors.policies.get("/v1/public/iam/session").allow("https://evil.example.com"), Some(AllowedOrigin::Any)));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,58 @@
+use rand::distributions::{Alphanumeric, DistString};
+use rocket::Request;
+use rocket::http::Method;
+use crate::api_response::ApiError;
+
+/*
+Double-submit CSRF protection for cookie sessions. Login sets a random token in the `nys-csrf` cookie, which
+(unlike `nys-session`) scripts on the page can read. State-changing requests must echo it in `X-CSRF-Token`.
+Another site can make the browser send the cookie, but cannot read it to fill in the header.
+Requests authenticated with `Authorization: Bearer` carry no ambient credentials and are not checked.
+ */
+
+pub const CSRF_COOKIE: &str = "nys-csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+pub fn generate_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+/// Safe methods must not change state, so they don't need the token.
+fn is_safe(method: Method) -> bool {
+    matches!(method, Method::Get | Method::Head | Method::Options)
+}
+
+/// Compares in constant time, so response timing doesn't reveal how much of a guess was right.
+fn tokens_match(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len() && expected.bytes().zip(provided.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Checks a cookie-authenticated request. Call only once the request is known to rely on the session cookie.
+pub fn verify(req: &Request<'_>) -> Result<(), ApiError> {
+    if is_safe(req.method()) {
+        return Ok(());
+    }
+
+    let cookie = req.cookies().get(CSRF_COOKIE).map(|cookie| cookie.value());
+    let header = req.headers().get_one(CSRF_HEADER);
+
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() && tokens_match(cookie, header) => Ok(()),
+        _ => Err(ApiError::CsrfTokenMismatch),
+    }
+}
+
+#[cfg(test)]
+mod csrf_tests {
+    use super::*;
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        let token = generate_token();
+
+        assert!(tokens_match(&token, &token.clone()));
+        assert!(!tokens_match(&token, &token[1..]));
+        assert!(!tokens_match(&token, &generate_token()));
+        assert!(!is_safe(Method::Delete));
+    }
+}
@@ -1,13 +1,143 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use aws_sdk_dynamodb::Endpoint;
+use aws_sdk_dynamodb::model::AttributeValue;
+use aws_types::region::Region;
+use crate::config::{AwsConfig, TablesConfig};
+
+/// Builds the DynamoDB client. Static credentials and region are used when configured, the default AWS provider chain otherwise.
+pub async fn connect(aws: &AwsConfig) -> Result<aws_sdk_dynamodb::Client, Box<dyn std::error::Error>> {
+    let mut loader = aws_config::from_env();
+
+    if let Some(region) = &aws.region {
+        loader = loader.region(Region::new(region.clone()));
+    }
+
+    if let (Some(access_id), Some(secret)) = (&aws.access_id, &aws.secret) {
+        loader = loader.credentials_provider(aws_types::Credentials::new(access_id, secret, None, None, "Application Config"));
+    }
+
+    let sdk_config = loader.load().await;
+    let mut ddb_config = aws_sdk_dynamodb::config::Builder::from(&sdk_config);
+
+    if let Some(endpoint) = &aws.dynamodb_endpoint {
+        ddb_config = ddb_config.endpoint_resolver(Endpoint::immutable(endpoint.parse()?));
+    }
+
+    Ok(aws_sdk_dynamodb::Client::from_conf(ddb_config.build()))
+}
+
+static TABLE_NAMES: OnceLock<[String; 6]> = OnceLock::new();
+
+/// Fixes the physical table names from the configuration. Must run once at startup, before any request.
+pub fn init(tables: &TablesConfig) {
+    let _ = TABLE_NAMES.set(Table::ALL.map(|table| table.resolve(tables)));
+}
+
+/// The only place table names come from; handlers never spell them out.
+#[derive(Clone, Copy)]
 pub enum Table {
-    IAM,
-    TASKER,
+    Iam,
+    Tasker,
+    /// Bookkeeping for the service itself, e.g. the applied schema version.
+    Meta,
+    Audit,
+    Webhooks,
+    WebhookDeliveries,
 }
 
 impl Table {
+    pub const ALL: [Table; 6] = [Table::Iam, Table::Tasker, Table::Meta, Table::Audit, Table::Webhooks, Table::WebhookDeliveries];
+
+    /// The physical name under the given configuration: the environment prefix followed by the table's configured name.
+    pub fn resolve(&self, tables: &TablesConfig) -> String {
+        let name = match self {
+            Table::Iam => &tables.iam,
+            Table::Tasker => &tables.tasker,
+            Table::Meta => &tables.meta,
+            Table::Audit => &tables.audit,
+            Table::Webhooks => &tables.webhooks,
+            Table::WebhookDeliveries => &tables.webhook_deliveries,
+        };
+
+        format!["{}{}", tables.prefix, name]
+    }
+
     pub fn as_str(&self) -> &'static str {
-        match self {
-            Table::IAM => "NYS_iam",
-            Table::TASKER => "NYS_tasker",
-        }
+        let names = TABLE_NAMES.get_or_init(|| Table::ALL.map(|table| table.resolve(&TablesConfig::default())));
+
+        &names[*self as usize]
+    }
+}
+
+/// DynamoDB accepts 3-255 characters out of `[A-Za-z0-9_.-]`.
+pub fn is_valid_table_name(name: &str) -> bool {
+    (3..=255).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Milliseconds since the Unix epoch, as stored items are stamped and sorted by.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since_epoch| since_epoch.as_millis() as u64).unwrap_or(0)
+}
+
+/// Page size of the list endpoints when the client doesn't pass a `limit`.
+pub const DEFAULT_PAGE_SIZE: i32 = 50;
+
+/// The largest `limit` a client may ask for, anything above is clamped.
+pub const MAX_PAGE_SIZE: i32 = 200;
+
+/// The DynamoDB `Limit` for a client's requested page size.
+pub fn page_size(limit: Option<i32>) -> i32 {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+/// The most writes `BatchWriteItem` accepts per call.
+pub const BATCH_WRITE_CHUNK: usize = 25;
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// Pagination cursor for a `LastEvaluatedKey`, the `next_cursor` of list responses that clients pass back as `cursor`.
+/// Our keys only hold strings, so the cursor is just those strings, JSON encoded and made URL-safe.
+pub fn encode_cursor(key: &HashMap<String, AttributeValue>) -> String {
+    let key: HashMap<&str, &str> = key.iter()
+        .filter_map(|(name, value)| value.as_s().ok().map(|value| (name.as_str(), value.as_str())))
+        .collect();
+
+    base32::encode(BASE32, serde_json::to_string(&key).unwrap_or_default().as_bytes())
+}
+
+/// The `ExclusiveStartKey` for a cursor from `encode_cursor`, `None` if it isn't one.
+pub fn decode_cursor(cursor: &str) -> Option<HashMap<String, AttributeValue>> {
+    let key: HashMap<String, String> = serde_json::from_slice(&base32::decode(BASE32, cursor)?).ok()?;
+
+    Some(key.into_iter().map(|(name, value)| (name, AttributeValue::S(value))).collect())
+}
+
+#[cfg(test)]
+mod db_tests {
+    use super::*;
+
+    #[test]
+    fn prefix_is_applied_to_every_table() {
+        let tables = TablesConfig {
+            prefix: "staging_".to_string(),
+            ..TablesConfig::default()
+        };
+
+        assert_eq!(Table::Iam.resolve(&tables), "staging_NYS_iam");
+        assert_eq!(Table::Tasker.resolve(&tables), "staging_NYS_tasker");
+        assert_eq!(Table::Meta.resolve(&tables), "staging_NYS_meta");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cursor_round_trips() {
+        let key = HashMap::from([
+            ("actor".to_string(), AttributeValue::S("nate".to_string())),
+            ("id".to_string(), AttributeValue::S("0001700000000000#abc".to_string())),
+        ]);
+
+        assert_eq!(decode_cursor(&encode_cursor(&key)), Some(key));
+        assert_eq!(decode_cursor("not a cursor"), None);
+    }
+}
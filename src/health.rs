@@ -0,0 +1,80 @@
+use std::time::Instant;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use tracing::Instrument;
+use crate::authorization::guarded_routes;
+use crate::db;
+use crate::metrics;
+use crate::telemetry::RequestSpan;
+
+#[derive(serde::Serialize)]
+pub struct Liveness {
+    status: &'static str,
+}
+
+#[derive(serde::Serialize)]
+pub struct DependencyStatus {
+    name: String,
+    ready: bool,
+    latency_ms: f64,
+}
+
+#[derive(serde::Serialize)]
+pub struct Readiness {
+    ready: bool,
+    dependencies: Vec<DependencyStatus>,
+}
+
+/// Only says the process is up and serving; dependencies are deliberately not consulted.
+#[rocket::get("/health")]
+pub fn health() -> Json<Liveness> {
+    Json(Liveness { status: "ok" })
+}
+
+/// Checks every dependency needed to serve traffic. Answers 503 if any of them is unavailable.
+#[rocket::get("/ready")]
+pub async fn ready(span: RequestSpan, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>) -> Custom<Json<Readiness>> {
+    let mut dependencies = Vec::new();
+
+    // Redis
+    let started = Instant::now();
+    let ping: Result<String, redis::RedisError> = metrics::observe_sync("redis", "PING", || {
+        redis::cmd("PING").query(&mut redis_client.get_connection()?)
+    });
+
+    if let Err(err) = &ping {
+        tracing::warn!(parent: &*span, error = %err, "readiness check failed for redis");
+    }
+
+    dependencies.push(DependencyStatus {
+        name: "redis".to_string(),
+        ready: ping.is_ok(),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+    });
+
+    // DynamoDB, one cheap control plane call per table
+    for table in db::Table::ALL {
+        let started = Instant::now();
+        let describe = metrics::observe("dynamodb", "DescribeTable", db_client.describe_table().table_name(table.as_str()).send())
+            .instrument(tracing::info_span!(parent: &*span, "dynamodb", operation = "DescribeTable", table = table.as_str()))
+            .await;
+
+        if let Err(err) = &describe {
+            tracing::warn!(parent: &*span, error = %err, table = table.as_str(), "readiness check failed for dynamodb");
+        }
+
+        dependencies.push(DependencyStatus {
+            name: format!["dynamodb:{}", table.as_str()],
+            ready: describe.is_ok(),
+            latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        });
+    }
+
+    let ready = dependencies.iter().all(|dependency| dependency.ready);
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+
+    Custom(status, Json(Readiness { ready, dependencies }))
+}
+
+guarded_routes![health, ready];
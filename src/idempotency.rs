@@ -0,0 +1,180 @@
+use hmac::Mac;
+use redis::Commands;
+use rocket::Request;
+use rocket::request::{FromRequest, Outcome};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::api_response::{guard_failure, ApiError};
+use crate::config::IdempotencyConfig;
+use crate::metrics;
+
+/*
+`Idempotency-Key` support for create endpoints. The first request with a key reserves it in the cache,
+and on success its response replaces the reservation for `idempotency.window_seconds`. Retries with the
+same key and body get that response back instead of creating a second resource. Reusing a key with a
+different body is an error, as is retrying while the first request is still running. Failed requests
+release their key so they can be retried. Bodies are compared by a fingerprint keyed with the server-side
+`idempotency.fingerprint_secret`, so a cached record doesn't allow guessing a body, e.g. a password, offline. The reservation itself only lives for `PENDING_TTL_SECONDS`, so a
+request that dies before settling (a crash, a panic) doesn't block its key for the whole window.
+ */
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// How long a reservation outlives a request that never settles it. Well above how long any create takes.
+const PENDING_TTL_SECONDS: usize = 60;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum Record {
+    Pending { fingerprint: String },
+    Completed { fingerprint: String, response: serde_json::Value },
+}
+
+/// The `Idempotency-Key` header of a request, if any.
+pub struct IdempotencyKey(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one(IDEMPOTENCY_KEY_HEADER) {
+            None => Outcome::Success(IdempotencyKey(None)),
+            Some(key) if is_valid_key(key) => Outcome::Success(IdempotencyKey(Some(key.to_string()))),
+            Some(_) => guard_failure(req, ApiError::InvalidHeader),
+        }
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+pub enum Claim<T> {
+    /// The key already completed with this response; send it again.
+    Replay(T),
+    /// Handle the request, then `settle` the reservation with the outcome.
+    Proceed(Reservation),
+}
+
+pub struct Reservation(Option<ReservedKey>);
+
+struct ReservedKey {
+    cache_key: String,
+    fingerprint: String,
+    window_seconds: usize,
+}
+
+impl IdempotencyKey {
+    /// Reserves the key within `scope` (the operation and whoever performs it) for a request with body `request`.
+    pub fn claim<T: DeserializeOwned>(&self, redis_client: &redis::Client, config: &IdempotencyConfig, scope: &str, request: &impl Serialize) -> Result<Claim<T>, ApiError> {
+        let key = match &self.0 {
+            Some(key) => key,
+            None => return Ok(Claim::Proceed(Reservation(None))),
+        };
+
+        let cache_key = format!["idempotency:{}:{}", scope, key];
+        let fingerprint = fingerprint(&config.fingerprint_secret, &cache_key, &serde_json::to_vec(request)?);
+        let window_seconds = config.window_seconds;
+        let pending = serde_json::to_string(&Record::Pending { fingerprint: fingerprint.clone() })?;
+
+        let mut conn = redis_client.get_connection()?;
+
+        let reserved: Option<String> = metrics::observe_sync("redis", "SET", || {
+            redis::cmd("SET").arg(&cache_key).arg(pending).arg("NX").arg("EX").arg(PENDING_TTL_SECONDS.min(window_seconds)).query(&mut conn)
+        })?;
+
+        if reserved.is_some() {
+            return Ok(Claim::Proceed(Reservation(Some(ReservedKey { cache_key, fingerprint, window_seconds }))));
+        }
+
+        let existing: Option<String> = metrics::observe_sync("redis", "GET", || conn.get(&cache_key))?;
+
+        // Expired between the two commands, which is as good as never having been used
+        let existing: Record = match existing {
+            Some(existing) => serde_json::from_str(&existing)?,
+            None => return self.claim(redis_client, config, scope, request),
+        };
+
+        match existing {
+            Record::Pending { fingerprint: stored } | Record::Completed { fingerprint: stored, .. } if stored != fingerprint => {
+                Err(ApiError::IdempotencyKeyReused)
+            },
+            Record::Pending { .. } => Err(ApiError::IdempotencyKeyInProgress),
+            Record::Completed { response, .. } => Ok(Claim::Replay(serde_json::from_value(response)?)),
+        }
+    }
+}
+
+impl Reservation {
+    /// Stores a successful response for replay, or releases the key after a failure so the client can retry.
+    /// By now the request has had its effect, so a cache failure is only logged rather than failing the request.
+    pub fn settle<T: Serialize>(self, redis_client: &redis::Client, outcome: &Result<T, ApiError>) {
+        let reserved = match self.0 {
+            Some(reserved) => reserved,
+            None => return,
+        };
+
+        if let Err(err) = reserved.settle(redis_client, outcome) {
+            tracing::error!(error = %err, cache_key = %reserved.cache_key, "failed to settle idempotency key");
+        }
+    }
+}
+
+impl ReservedKey {
+    fn settle<T: Serialize>(&self, redis_client: &redis::Client, outcome: &Result<T, ApiError>) -> Result<(), ApiError> {
+        let mut conn = redis_client.get_connection()?;
+
+        let _ : () = match outcome {
+            Ok(response) => {
+                let completed = serde_json::to_string(&Record::Completed {
+                    fingerprint: self.fingerprint.clone(),
+                    response: serde_json::to_value(response)?,
+                })?;
+
+                metrics::observe_sync("redis", "SET", || conn.set_ex(&self.cache_key, completed, self.window_seconds))
+            },
+            Err(_) => metrics::observe_sync("redis", "DEL", || conn.del(&self.cache_key)),
+        }?;
+
+        Ok(())
+    }
+}
+
+/// Covers the cache key too, so equal bodies under different keys don't share a fingerprint.
+fn fingerprint(secret: &str, cache_key: &str, body: &[u8]) -> String {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    // Keys are printable, so the newline can't be part of one
+    mac.update(cache_key.as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+
+    mac.finalize().into_bytes().iter().map(|byte| format!["{:02x}", byte]).collect()
+}
+
+#[cfg(test)]
+mod idempotency_tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn fingerprint_depends_on_secret_key_and_body() {
+        let original = fingerprint(SECRET, "idempotency:create_task:nate:1", b"{\"description\":\"a\"}");
+
+        assert_eq!(original, fingerprint(SECRET, "idempotency:create_task:nate:1", b"{\"description\":\"a\"}"));
+        assert_ne!(original, fingerprint(SECRET, "idempotency:create_task:nate:1", b"{\"description\":\"b\"}"));
+        assert_ne!(original, fingerprint(SECRET, "idempotency:create_task:nate:2", b"{\"description\":\"a\"}"));
+        assert_ne!(original, fingerprint("fedcba9876543210fedcba9876543210", "idempotency:create_task:nate:1", b"{\"description\":\"a\"}"));
+    }
+
+    #[test]
+    fn keys_must_be_printable() {
+        assert!(is_valid_key("4f1c0a7e-retry"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("has space"));
+        assert!(!is_valid_key(&"k".repeat(MAX_KEY_LENGTH + 1)));
+    }
+}
@@ -1,43 +1,96 @@
-use aws_types::credentials::SharedCredentialsProvider;
-
 mod public;
 mod private;
 mod cors;
 mod db;
 mod api_response;
+mod authorization;
+mod totp;
+mod request_id;
+mod telemetry;
+mod metrics;
+mod health;
+mod config;
+mod migrate;
+mod csrf;
+mod mounts;
+mod security_headers;
+mod audit;
+mod versioning;
+mod idempotency;
+mod task_events;
+mod webhooks;
 
 #[rocket::get("/")]
 fn index() -> &'static str {
     "Hello, world!"
 }
 
+authorization::guarded_routes![index];
+
+/// Every route set and where it's served, the permission catalog is checked against this.
+fn mount(rocket: rocket::Rocket<rocket::Build>) -> rocket::Rocket<rocket::Build> {
+    rocket
+        .mount("/", metrics::routes())
+        .mount("/", cors::routes())
+        .mount("/v1", routes())
+        .mount("/v1", health::routes())
+        .mount("/v1/private/tasker", private::tasker::routes())
+        .mount("/v1/private/tasker", private::webhooks::routes())
+        .mount("/v1/private/tasker", private::task_history::routes())
+        .mount("/v1/private/debug", private::debug::routes())
+        .mount("/v1/private/mfa", private::mfa::routes())
+        .mount("/v1/private/audit", private::audit::routes())
+        .mount("/v1/public/iam", public::iam::routes())
+        .mount("/v1/public/info", public::info::routes())
+}
+
 #[rocket::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Configuration, every missing or invalid key is reported at once
+    let figment = config::AppConfig::figment();
+    let config = config::AppConfig::load(&figment)?;
+    db::init(&config.tables);
+
+    // Logging, `log_format = "json"` (or NYS_LOG_FORMAT=json) switches to structured JSON output
+    telemetry::init(config.log_format == config::LogFormat::Json).map_err(|err| err as Box<dyn std::error::Error>)?;
+
     // Connect to AWS
-    let akid = std::env::var("AWS_ACCESS_ID")?;
-    let secret = std::env::var("AWS_SECRET")?;
-    let region = std::env::var("AWS_REGION")?;
-    let credentials = aws_types::Credentials::new(akid, secret, None, None, "System Environment");
-    let ddb_config = aws_types::SdkConfig::builder()
-        .credentials_provider(SharedCredentialsProvider::new(credentials))
-        .region(aws_types::region::Region::new(region))
-        .build();
+    let ddb_client = db::connect(&config.aws).await?;
+
+    // `not-your-api migrate` provisions the tables and exits instead of serving
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        return migrate::run(&ddb_client).await;
+    }
 
-    let ddb_client = aws_sdk_dynamodb::Client::new(&ddb_config);
+    audit::spawn_writer(ddb_client.clone());
+    webhooks::spawn_dispatcher(ddb_client.clone(), &config.webhooks);
 
     // Connect to REDIS
-    let redis_url = std::env::var("REDIS")?;
-    let redis_client = redis::Client::open(redis_url)?;
+    let redis_client = redis::Client::open(config.redis_url.as_str())?;
+    let task_events = task_events::spawn_listener(redis_client.clone());
 
     // Start
-    let _rocket = rocket::build()
-        .mount("/v1", rocket::routes![index])
-        .mount("/v1/private/tasker", private::tasker::routes())
-        .mount("/v1/private/debug", private::debug::routes())
-        .mount("/v1/public/iam", public::iam::routes())
-        .attach(cors::CORS)
+    let cors = cors::Cors::new(cors::CorsPolicy::new(config.cors.public_allowed_origins.clone(), config.cors.max_age_seconds))
+        .mount("/v1/private", cors::CorsPolicy::new(config.cors.private_allowed_origins.clone(), config.cors.max_age_seconds));
+
+    // Credentials and session material must never end up in a cache
+    let header_policy = security_headers::HeaderPolicy::new(&config.security_headers);
+    let no_store = header_policy.clone().with("Cache-Control", "no-store");
+    let security_headers = security_headers::SecurityHeaders::new(header_policy)
+        .mount("/v1/public/iam", no_store.clone())
+        .mount("/v1/private/mfa", no_store);
+
+    let _rocket = mount(rocket::custom(figment))
+        .register("/", rocket::catchers![api_response::guard_failure_catcher])
+        .attach(request_id::RequestIdFairing)
+        .attach(telemetry::RequestTracing)
+        .attach(metrics::RequestMetrics)
+        .attach(cors)
+        .attach(security_headers)
         .manage(ddb_client)
         .manage(redis_client)
+        .manage(task_events)
+        .manage(config)
         .launch()
         .await?;
 
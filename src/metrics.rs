@@ -0,0 +1,173 @@
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+use rocket::{Data, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::request::{FromRequest, Outcome};
+use crate::api_response::{guard_failure, ApiError};
+use crate::authorization::{guarded_routes, MetricsRead, Permission};
+use crate::config::AppConfig;
+use crate::public::iam::{client_address, ip_in_range, AuthenticatableEntity};
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).expect("metric registered twice");
+    metric
+}
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("nys_http_requests_total", "HTTP requests by route, method and status."),
+    &["route", "method", "status"],
+).unwrap()));
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register(HistogramVec::new(
+    HistogramOpts::new("nys_http_request_duration_seconds", "HTTP request latency by route, method and status."),
+    &["route", "method", "status"],
+).unwrap()));
+
+static LOGINS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("nys_logins_total", "Login attempts by outcome."),
+    &["outcome"],
+).unwrap()));
+
+static AE_CACHE: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("nys_ae_cache_lookups_total", "AE cache lookups by result (hit or miss)."),
+    &["result"],
+).unwrap()));
+
+static DEPENDENCY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register(HistogramVec::new(
+    HistogramOpts::new("nys_dependency_duration_seconds", "Latency of calls to DynamoDB and Redis."),
+    &["dependency", "operation"],
+).unwrap()));
+
+static DEPENDENCY_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("nys_dependency_errors_total", "Failed calls to DynamoDB and Redis."),
+    &["dependency", "operation"],
+).unwrap()));
+
+pub enum LoginOutcome {
+    Success,
+    Failure,
+    MfaRequired,
+    MfaSuccess,
+    MfaFailure,
+}
+
+pub fn record_login(outcome: LoginOutcome) {
+    let outcome = match outcome {
+        LoginOutcome::Success => "success",
+        LoginOutcome::Failure => "failure",
+        LoginOutcome::MfaRequired => "mfa_required",
+        LoginOutcome::MfaSuccess => "mfa_success",
+        LoginOutcome::MfaFailure => "mfa_failure",
+    };
+
+    LOGINS.with_label_values(&[outcome]).inc();
+}
+
+pub fn record_ae_cache_lookup(hit: bool) {
+    AE_CACHE.with_label_values(&[if hit { "hit" } else { "miss" }]).inc();
+}
+
+fn record_dependency_call(dependency: &str, operation: &str, started: Instant, failed: bool) {
+    DEPENDENCY_DURATION.with_label_values(&[dependency, operation]).observe(started.elapsed().as_secs_f64());
+
+    if failed {
+        DEPENDENCY_ERRORS.with_label_values(&[dependency, operation]).inc();
+    }
+}
+
+/// Times an asynchronous dependency call (e.g. a DynamoDB request) and counts its failures.
+pub async fn observe<T, E>(dependency: &str, operation: &str, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let started = Instant::now();
+    let result = call.await;
+    record_dependency_call(dependency, operation, started, result.is_err());
+
+    result
+}
+
+/// Synchronous counterpart of `observe`, for Redis commands.
+pub fn observe_sync<T, E>(dependency: &str, operation: &str, call: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let started = Instant::now();
+    let result = call();
+    record_dependency_call(dependency, operation, started, result.is_err());
+
+    result
+}
+
+struct MetricsStart(Instant);
+
+/// Counts and times every request by matched route template, method and status.
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| MetricsStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        let elapsed = req.local_cache(|| MetricsStart(Instant::now())).0.elapsed();
+
+        // Route templates rather than raw paths keep label cardinality bounded
+        let route = req.route().map_or_else(|| "unmatched".to_string(), |route| route.uri.to_string());
+        let method = req.method().as_str();
+        let status = response.status().code.to_string();
+        let labels = [route.as_str(), method, status.as_str()];
+
+        HTTP_REQUESTS.with_label_values(&labels).inc();
+        HTTP_REQUEST_DURATION.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+}
+
+/// Grants access to `/metrics` to allowlisted scrapers, or to AEs holding `nys:metrics:*:Metrics:Read`.
+pub struct MetricsAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAccess {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let allowlisted = match (req.rocket().state::<AppConfig>(), client_address(req)) {
+            (Some(config), Some(client_address)) => config.metrics_allowed_sources.iter().any(|range| ip_in_range(client_address, range)),
+            _ => false,
+        };
+
+        if allowlisted {
+            return Outcome::Success(MetricsAccess);
+        }
+
+        let ae = match req.guard::<AuthenticatableEntity>().await {
+            Outcome::Success(ae) => ae,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+
+        match ae.assert_privilege(MetricsRead::TEMPLATE.to_string()) {
+            Ok(()) => Outcome::Success(MetricsAccess),
+            Err(err) => guard_failure(req, err),
+        }
+    }
+}
+
+#[rocket::get("/metrics")]
+pub fn metrics(_access: MetricsAccess) -> (ContentType, String) {
+    let mut buffer = Vec::new();
+
+    // Encoding into a Vec cannot fail for well-formed metric families
+    let _ = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer);
+
+    (ContentType::new("text", "plain").with_params(("version", "0.0.4")), String::from_utf8(buffer).unwrap_or_default())
+}
+
+guarded_routes![metrics];
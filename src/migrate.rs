@@ -0,0 +1,256 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::model::{AttributeDefinition, AttributeValue, BillingMode, CreateGlobalSecondaryIndexAction, GlobalSecondaryIndexUpdate, IndexStatus, KeySchemaElement, KeyType, Projection, ProjectionType, ScalarAttributeType, TableStatus};
+use aws_sdk_dynamodb::types::SdkError;
+use crate::db::Table;
+
+/*
+`not-your-api migrate` provisions an environment from scratch and is safe to run on every deploy:
+1. Every table in `db::Table` is created with its primary key if it does not exist yet.
+2. Every migration newer than the version recorded in the meta table is applied, in order, and recorded.
+ */
+
+type MigrationResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Meta table item holding the last applied migration.
+const SCHEMA_VERSION_ID: &str = "schema_version";
+
+/// How long to wait for tables and indexes to become usable.
+const ACTIVE_TIMEOUT: Duration = Duration::from_secs(600);
+
+pub struct KeySchema {
+    partition: &'static str,
+    sort: Option<&'static str>,
+}
+
+impl KeySchema {
+    fn attribute_definitions(&self) -> Vec<AttributeDefinition> {
+        std::iter::once(self.partition).chain(self.sort)
+            .map(|name| AttributeDefinition::builder().attribute_name(name).attribute_type(ScalarAttributeType::S).build())
+            .collect()
+    }
+
+    fn key_schema(&self) -> Vec<KeySchemaElement> {
+        let mut elements = vec![KeySchemaElement::builder().attribute_name(self.partition).key_type(KeyType::Hash).build()];
+
+        if let Some(sort) = self.sort {
+            elements.push(KeySchemaElement::builder().attribute_name(sort).key_type(KeyType::Range).build());
+        }
+
+        elements
+    }
+}
+
+/// Primary key of every table, all attributes are strings.
+pub fn primary_key(table: Table) -> KeySchema {
+    match table {
+        Table::Iam => KeySchema { partition: "id", sort: None },
+        Table::Tasker => KeySchema { partition: "owner", sort: Some("id") },
+        Table::Meta => KeySchema { partition: "id", sort: None },
+        Table::Audit => KeySchema { partition: "actor", sort: Some("id") },
+        Table::Webhooks => KeySchema { partition: "owner", sort: Some("id") },
+        Table::WebhookDeliveries => KeySchema { partition: "subscription", sort: Some("id") },
+    }
+}
+
+pub enum Step {
+    CreateIndex {
+        table: Table,
+        name: &'static str,
+        key: KeySchema,
+    },
+}
+
+pub struct Migration {
+    version: u32,
+    description: &'static str,
+    steps: &'static [Step],
+}
+
+/// Append only. Versions must keep increasing and applied migrations must never change.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "index audit events by day",
+        steps: &[
+            Step::CreateIndex { table: Table::Audit, name: "day-index", key: KeySchema { partition: "day", sort: Some("id") } },
+        ],
+    },
+];
+
+/// The migrations still to apply on top of `applied`, oldest first.
+fn pending(migrations: &'static [Migration], applied: u32) -> impl Iterator<Item = &'static Migration> {
+    migrations.iter().filter(move |migration| migration.version > applied)
+}
+
+pub async fn run(db_client: &Client) -> MigrationResult<()> {
+    for table in Table::ALL {
+        ensure_table(db_client, table).await?;
+    }
+
+    let mut applied = schema_version(db_client).await?;
+    tracing::info!(version = applied, "current schema version");
+
+    for migration in pending(MIGRATIONS, applied) {
+        tracing::info!(version = migration.version, description = migration.description, "applying migration");
+
+        for step in migration.steps {
+            apply(db_client, step).await?;
+        }
+
+        record_version(db_client, applied, migration).await?;
+        applied = migration.version;
+    }
+
+    tracing::info!(version = applied, "schema is up to date");
+
+    Ok(())
+}
+
+async fn ensure_table(db_client: &Client, table: Table) -> MigrationResult<()> {
+    match db_client.describe_table().table_name(table.as_str()).send().await {
+        Ok(_) => tracing::info!(table = table.as_str(), "table exists"),
+        Err(SdkError::ServiceError { err, .. }) if err.is_resource_not_found_exception() => {
+            let key = primary_key(table);
+
+            let create = db_client.create_table()
+                .table_name(table.as_str())
+                .set_attribute_definitions(Some(key.attribute_definitions()))
+                .set_key_schema(Some(key.key_schema()))
+                .billing_mode(BillingMode::PayPerRequest)
+                .send().await;
+
+            match create {
+                Ok(_) => tracing::info!(table = table.as_str(), "created table"),
+                // Someone else got there first, which is just as good
+                Err(SdkError::ServiceError { err, .. }) if err.is_resource_in_use_exception() => {},
+                Err(err) => return Err(err.into()),
+            }
+        },
+        Err(err) => return Err(err.into()),
+    }
+
+    wait_until_active(db_client, table).await
+}
+
+/// Waits for the table and all of its indexes, DynamoDB refuses schema changes while anything is still being built.
+async fn wait_until_active(db_client: &Client, table: Table) -> MigrationResult<()> {
+    let started = std::time::Instant::now();
+
+    loop {
+        let description = db_client.describe_table().table_name(table.as_str()).send().await?;
+        let description = description.table();
+
+        let table_active = description.and_then(|description| description.table_status()) == Some(&TableStatus::Active);
+        let indexes_active = description
+            .and_then(|description| description.global_secondary_indexes())
+            .unwrap_or_default()
+            .iter()
+            .all(|index| index.index_status() == Some(&IndexStatus::Active));
+
+        if table_active && indexes_active {
+            return Ok(());
+        }
+
+        if started.elapsed() > ACTIVE_TIMEOUT {
+            return Err(format!["table `{}` did not become active within {:?}", table.as_str(), ACTIVE_TIMEOUT].into());
+        }
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+async fn apply(db_client: &Client, step: &Step) -> MigrationResult<()> {
+    match step {
+        Step::CreateIndex { table, name, key } => {
+            let description = db_client.describe_table().table_name(table.as_str()).send().await?;
+
+            let exists = description.table()
+                .and_then(|description| description.global_secondary_indexes())
+                .unwrap_or_default()
+                .iter()
+                .any(|index| index.index_name() == Some(*name));
+
+            if exists {
+                tracing::info!(table = table.as_str(), index = name, "index exists");
+                return Ok(());
+            }
+
+            let index = CreateGlobalSecondaryIndexAction::builder()
+                .index_name(*name)
+                .set_key_schema(Some(key.key_schema()))
+                .projection(Projection::builder().projection_type(ProjectionType::All).build())
+                .build();
+
+            db_client.update_table()
+                .table_name(table.as_str())
+                .set_attribute_definitions(Some(key.attribute_definitions()))
+                .global_secondary_index_updates(GlobalSecondaryIndexUpdate::builder().create(index).build())
+                .send().await?;
+
+            tracing::info!(table = table.as_str(), index = name, "creating index");
+
+            wait_until_active(db_client, *table).await
+        },
+    }
+}
+
+async fn schema_version(db_client: &Client) -> MigrationResult<u32> {
+    let item = db_client.get_item()
+        .table_name(Table::Meta.as_str())
+        .key("id", AttributeValue::S(SCHEMA_VERSION_ID.to_string()))
+        .consistent_read(true)
+        .send().await?;
+
+    match item.item().and_then(|item| item.get("version")) {
+        Some(AttributeValue::N(version)) => Ok(version.parse()?),
+        Some(_) => Err("schema version item is malformed".into()),
+        None => Ok(0),
+    }
+}
+
+/// Conditional on the previous version, so two concurrent runs cannot both record the same migration.
+async fn record_version(db_client: &Client, previous: u32, migration: &Migration) -> MigrationResult<()> {
+    let applied_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    let put = db_client.put_item()
+        .table_name(Table::Meta.as_str())
+        .item("id", AttributeValue::S(SCHEMA_VERSION_ID.to_string()))
+        .item("version", AttributeValue::N(migration.version.to_string()))
+        .item("description", AttributeValue::S(migration.description.to_string()))
+        .item("applied_at", AttributeValue::N(applied_at.to_string()));
+
+    let put = if previous == 0 {
+        put.condition_expression("attribute_not_exists(id)")
+    } else {
+        put.condition_expression("version = :previous")
+            .expression_attribute_values(":previous", AttributeValue::N(previous.to_string()))
+    };
+
+    match put.send().await {
+        Ok(_) => Ok(()),
+        Err(SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => {
+            Err(format!["schema version changed while applying migration {}, another migration run is in progress", migration.version].into())
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod migrate_tests {
+    use super::*;
+
+    #[test]
+    fn migration_versions_increase() {
+        assert!(MIGRATIONS.windows(2).all(|pair| pair[0].version < pair[1].version));
+        assert!(MIGRATIONS.iter().all(|migration| migration.version > 0));
+    }
+
+    #[test]
+    fn only_newer_migrations_are_pending() {
+        let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+
+        assert_eq!(pending(MIGRATIONS, 0).count(), MIGRATIONS.len());
+        assert_eq!(pending(MIGRATIONS, latest).count(), 0);
+    }
+}
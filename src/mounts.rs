@@ -0,0 +1,39 @@
+/// A value chosen by mount point, the most specific mount covering a path wins.
+pub struct PerMount<P> {
+    fallback: P,
+    mounts: Vec<(String, P)>,
+}
+
+impl<P> PerMount<P> {
+    /// `fallback` applies to every path not covered by a more specific `mount`.
+    pub fn new(fallback: P) -> PerMount<P> {
+        PerMount { fallback, mounts: Vec::new() }
+    }
+
+    pub fn mount(mut self, base: &str, value: P) -> PerMount<P> {
+        self.mounts.push((base.trim_end_matches('/').to_string(), value));
+        self
+    }
+
+    pub fn get(&self, path: &str) -> &P {
+        self.mounts.iter()
+            .filter(|(base, _)| path == base || path.strip_prefix(base.as_str()).is_some_and(|rest| rest.starts_with('/')))
+            .max_by_key(|(base, _)| base.len())
+            .map_or(&self.fallback, |(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod mounts_tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_mount_wins() {
+        let mounts = PerMount::new("root").mount("/v1/private", "private").mount("/v1/private/mfa/", "mfa");
+
+        assert_eq!(*mounts.get("/v1/private/tasker/me/task"), "private");
+        assert_eq!(*mounts.get("/v1/private"), "private");
+        assert_eq!(*mounts.get("/v1/private/mfa/totp"), "mfa");
+        assert_eq!(*mounts.get("/v1/privateer"), "root");
+    }
+}
@@ -0,0 +1,36 @@
+use rocket::serde::json::Json;
+use crate::api_response::{ApiError, ApiResponse, ApiReturnValue};
+use crate::audit::{self, AuditPage, AuditQuery};
+use crate::authorization::{guarded_routes, AuditLogRead, Authorized};
+use crate::db;
+
+/// Filters are optional; `from`/`to` are unix seconds. Without `actor`, one `day` (YYYY-MM-DD) is searched at a time.
+#[allow(clippy::too_many_arguments)]
+#[rocket::get("/?<actor>&<day>&<action>&<outcome>&<from>&<to>&<limit>&<cursor>")]
+pub async fn get_audit_events(
+    _ae: Authorized<AuditLogRead>,
+    db_client: &rocket::State<aws_sdk_dynamodb::Client>,
+    actor: Option<&str>,
+    day: Option<&str>,
+    action: Option<&str>,
+    outcome: Option<&str>,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<i32>,
+    cursor: Option<&str>,
+) -> ApiReturnValue<AuditPage> {
+    let query = AuditQuery {
+        actor: actor.map(str::to_string),
+        day: day.map(str::to_string),
+        action: action.map(|action| audit::parse_label(action).ok_or(ApiError::InvalidQueryParameter)).transpose()?,
+        outcome: outcome.map(|outcome| audit::parse_label(outcome).ok_or(ApiError::InvalidQueryParameter)).transpose()?,
+        from,
+        to,
+        limit: db::page_size(limit),
+        cursor: cursor.map(str::to_string),
+    };
+
+    Ok(ApiResponse(Json(audit::query(db_client, query).await?)))
+}
+
+guarded_routes![get_audit_events];
@@ -1,10 +1,78 @@
-use crate::api_response::{ApiEmptyReturnValue, ApiResponse, ApiReturnValue};
-use crate::public::iam::AuthenticatableEntity;
+use crate::authorization::{guarded_routes, RouteCatalog, RoutePermissions};
+use crate::api_response::{ApiError, ApiResponse, ApiReturnValue, ApiVersionedResponse, ApiVersionedReturnValue};
+use crate::public::iam::{AuthenticatableEntity, AuthenticatableEntityView, PermissionGrant};
 use rocket::serde::json::Json;
 
+#[derive(serde::Deserialize)]
+pub struct SimulateRB<'r> {
+    entity_id: Option<&'r str>,
+    permissions: Vec<&'r str>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PermissionSimulation {
+    permission: String,
+    allowed: bool,
+    malformed: bool,
+    matching_grant: Option<PermissionGrant>,
+}
+
+#[derive(serde::Serialize)]
+pub struct SimulateResponse {
+    entity_id: String,
+    results: Vec<PermissionSimulation>,
+}
+
 #[rocket::get("/whoami")]
-pub fn whoami(ae: AuthenticatableEntity) -> ApiReturnValue<AuthenticatableEntity> {
-    Ok(ApiResponse(Json(ae)))
+pub fn whoami(ae: AuthenticatableEntity) -> ApiVersionedReturnValue<AuthenticatableEntityView> {
+    Ok(ApiVersionedResponse::new(ae.version(), ae.view()))
+}
+
+#[rocket::get("/permissions")]
+pub fn route_permissions(_ae: AuthenticatableEntity, catalog: RouteCatalog) -> ApiReturnValue<Vec<RoutePermissions>> {
+    Ok(ApiResponse(Json(catalog.0)))
+}
+
+#[rocket::post("/simulate", data = "<simulation>")]
+pub async fn simulate(ae: AuthenticatableEntity, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, simulation: Json<SimulateRB<'_>>) -> ApiReturnValue<SimulateResponse> {
+    // Conditions are always evaluated against the caller's request
+    let context = ae.context().clone();
+
+    // Simulating on behalf of someone else exposes their grants, so it requires read access to them
+    let target = match simulation.entity_id {
+        Some(entity_id) if entity_id != ae.id => {
+            ae.assert_privilege(format!["nys:iam:{}:AuthenticatableEntity:Read", entity_id])?;
+            AuthenticatableEntity::retrieve(db_client, redis_client, entity_id.to_string(), false).await?
+        },
+        _ => ae,
+    };
+
+    let mut results = Vec::with_capacity(simulation.permissions.len());
+
+    for permission in &simulation.permissions {
+        let result = match target.permissions().matching_grant(permission, &context) {
+            Ok(grant) => PermissionSimulation {
+                permission: permission.to_string(),
+                allowed: grant.is_some(),
+                malformed: false,
+                matching_grant: grant.cloned(),
+            },
+            Err(ApiError::MalformedPermission) => PermissionSimulation {
+                permission: permission.to_string(),
+                allowed: false,
+                malformed: true,
+                matching_grant: None,
+            },
+            Err(err) => return Err(err),
+        };
+
+        results.push(result);
+    }
+
+    Ok(ApiResponse(Json(SimulateResponse {
+        entity_id: target.id,
+        results,
+    })))
 }
 
-pub fn routes() -> Vec<rocket::Route> { rocket::routes![whoami] }
\ No newline at end of file
+guarded_routes![whoami, simulate, route_permissions];
@@ -0,0 +1,176 @@
+use redis::Commands;
+use rocket::serde::json::Json;
+use crate::api_response::{ApiEmptyReturnValue, ApiError, ApiResponse, ApiReturnValue};
+use crate::authorization::{guarded_routes, Authorized, AuthenticatableEntityWrite};
+use crate::config::AppConfig;
+use crate::audit::{self, AuditAction, AuditEvent, AuditOutcome};
+use crate::public::iam::{AuthenticatableEntity, RequestContext};
+use crate::totp;
+use crate::versioning::IfMatch;
+
+const TOTP_ISSUER: &str = "NotYourSoftware";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Codes tried per MFA challenge, or per AE on its own enrollment, before further attempts are refused.
+pub(crate) const MFA_MAX_ATTEMPTS: u32 = 5;
+
+/// TOTP credential material stored on the AE. Never cached, never returned to clients.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct MfaEnrollment {
+    secret: String,
+    confirmed: bool,
+
+    #[serde(default)]
+    recovery_code_hashes: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used_step: Option<u64>,
+}
+
+impl MfaEnrollment {
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// Accepts either a current TOTP code or an unused recovery code, consuming whichever matched.
+    /// The enrollment must be persisted afterwards for the consumption to stick.
+    pub fn verify(&mut self, code: &str, context: &RequestContext) -> bool {
+        if let Some(step) = totp::verify(&self.secret, code, context.received_at, self.last_used_step) {
+            self.last_used_step = Some(step);
+            return true;
+        }
+
+        let recovery_code = code.trim().to_lowercase();
+        let matching_recovery_code = self.recovery_code_hashes.iter()
+            .position(|hash| bcrypt::verify(&recovery_code, hash).unwrap_or(false));
+
+        match matching_recovery_code {
+            Some(index) => {
+                self.recovery_code_hashes.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// Verifies a code against the AE's own enrollment, counting attempts like `complete_mfa_challenge` does: a session
+/// alone mustn't allow guessing codes, each wrong guess also costs a bcrypt check per remaining recovery code.
+fn verify_attempt(enrollment: &mut MfaEnrollment, code: &str, ae: &AuthenticatableEntity, config: &AppConfig, redis_client: &redis::Client) -> Result<(), ApiError> {
+    let attempts_key = format!("mfa-code-attempts:{}", ae.id);
+
+    let mut conn = redis_client.get_connection()?;
+
+    let attempts: u32 = conn.incr(&attempts_key, 1)?;
+    let _ : () = conn.expire(&attempts_key, config.session.mfa_challenge_ttl_seconds)?;
+
+    if attempts > MFA_MAX_ATTEMPTS {
+        return Err(ApiError::TooManyMfaAttempts);
+    }
+
+    if !enrollment.verify(code, ae.context()) {
+        return Err(ApiError::InvalidMfaCode);
+    }
+
+    let _ : () = conn.del(&attempts_key)?;
+
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+pub struct EnrollTotpResponse {
+    secret: String,
+    provisioning_uri: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct MfaCodeRB<'r> {
+    code: &'r str,
+}
+
+#[derive(serde::Serialize)]
+pub struct ConfirmTotpResponse {
+    recovery_codes: Vec<String>,
+}
+
+#[rocket::post("/totp")]
+pub async fn enroll_totp(ae: Authorized<AuthenticatableEntityWrite>, if_match: IfMatch, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>) -> ApiReturnValue<EnrollTotpResponse> {
+    // The session AE is a cached copy without credentials; edits must start from the primary store
+    let mut stored_ae = AuthenticatableEntity::load(db_client, ae.id.clone()).await?;
+    if_match.check(stored_ae.version())?;
+
+    if stored_ae.mfa_enabled() {
+        return Err(ApiError::MfaAlreadyEnabled);
+    }
+
+    let secret = totp::generate_secret();
+
+    stored_ae.set_mfa(Some(MfaEnrollment {
+        secret: secret.clone(),
+        confirmed: false,
+        recovery_code_hashes: Vec::new(),
+        last_used_step: None,
+    }));
+    stored_ae.save(db_client, redis_client).await?;
+
+    audit::record(AuditEvent::new(AuditAction::MfaEnrolled, AuditOutcome::Success, &ae.id, ae.context()));
+
+    Ok(ApiResponse(Json(EnrollTotpResponse {
+        provisioning_uri: totp::provisioning_uri(&secret, TOTP_ISSUER, &ae.id),
+        secret,
+    })))
+}
+
+#[rocket::post("/totp/confirm", data = "<confirmation>")]
+pub async fn confirm_totp(ae: Authorized<AuthenticatableEntityWrite>, if_match: IfMatch, config: &rocket::State<AppConfig>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, confirmation: Json<MfaCodeRB<'_>>) -> ApiReturnValue<ConfirmTotpResponse> {
+    let mut stored_ae = AuthenticatableEntity::load(db_client, ae.id.clone()).await?;
+    if_match.check(stored_ae.version())?;
+
+    let mut enrollment = match stored_ae.mfa() {
+        Some(enrollment) if enrollment.confirmed => return Err(ApiError::MfaAlreadyEnabled),
+        Some(enrollment) => enrollment.clone(),
+        None => return Err(ApiError::MfaNotEnrolled),
+    };
+
+    // Only a TOTP code proves the authenticator was set up; there are no recovery codes yet
+    verify_attempt(&mut enrollment, confirmation.code, &ae, config, redis_client)?;
+
+    let recovery_codes = totp::generate_recovery_codes(RECOVERY_CODE_COUNT);
+
+    enrollment.confirmed = true;
+    enrollment.recovery_code_hashes = recovery_codes.iter()
+        .map(|code| bcrypt::hash(code, 10))
+        .collect::<Result<_, _>>()?;
+
+    stored_ae.set_mfa(Some(enrollment));
+    stored_ae.save(db_client, redis_client).await?;
+
+    audit::record(AuditEvent::new(AuditAction::MfaEnabled, AuditOutcome::Success, &ae.id, ae.context()));
+
+    Ok(ApiResponse(Json(ConfirmTotpResponse { recovery_codes })))
+}
+
+#[rocket::delete("/totp", data = "<confirmation>")]
+pub async fn disable_totp(ae: Authorized<AuthenticatableEntityWrite>, if_match: IfMatch, config: &rocket::State<AppConfig>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, confirmation: Json<MfaCodeRB<'_>>) -> ApiEmptyReturnValue {
+    let mut stored_ae = AuthenticatableEntity::load(db_client, ae.id.clone()).await?;
+    if_match.check(stored_ae.version())?;
+
+    let mut enrollment = match stored_ae.mfa() {
+        Some(enrollment) => enrollment.clone(),
+        None => return Err(ApiError::MfaNotEnrolled),
+    };
+
+    // A pending enrollment can be abandoned freely, an active one needs a valid code
+    if enrollment.confirmed {
+        verify_attempt(&mut enrollment, confirmation.code, &ae, config, redis_client)?;
+    }
+
+    stored_ae.set_mfa(None);
+    stored_ae.save(db_client, redis_client).await?;
+
+    audit::record(AuditEvent::new(AuditAction::MfaDisabled, AuditOutcome::Success, &ae.id, ae.context()));
+
+    Ok(())
+}
+
+guarded_routes![enroll_totp, confirm_totp, disable_totp];
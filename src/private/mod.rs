@@ -1,2 +1,6 @@
 pub mod tasker;
-pub mod debug;
\ No newline at end of file
+pub mod debug;
+pub mod mfa;
+pub mod audit;
+pub mod webhooks;
+pub mod task_history;
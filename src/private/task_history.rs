@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use aws_sdk_dynamodb::model::{AttributeValue, PutRequest, WriteRequest};
+use rocket::serde::json::Json;
+use uuid::Uuid;
+use tracing::Instrument;
+use crate::api_response::{ApiError, ApiResponse, ApiReturnValue};
+use crate::authorization::{guarded_routes, Authorized, TaskListRead, TaskListWrite};
+use crate::db;
+use crate::metrics;
+use crate::private::tasker;
+use crate::telemetry::RequestSpan;
+
+/*
+Comments and activity history of tasks. Both are stored in the tasker table, in a partition of their own
+next to the list's (`<entity>#history`, entity ids can't contain `#`), under sort keys
+`<task id>#comment#<time>` and `<task id>#activity#<time>` so a task's entries of either kind can be read
+in order with one query. Listing tasks never reads them. Entries outlive their task, the last activity of a
+deleted task says who deleted it.
+ */
+
+const COMMENT_ENTRY: &str = "comment";
+const ACTIVITY_ENTRY: &str = "activity";
+
+const MAX_COMMENT_LENGTH: usize = 4000;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskAction {
+    Created,
+    Edited,
+    Completed,
+    Reopened,
+    Reassigned,
+    Deleted,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Activity {
+    /// The AE that acted.
+    actor: String,
+    action: TaskAction,
+    /// Unix millis.
+    at: u64,
+
+    /// What an edit changed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<String>,
+
+    /// Who a task was reassigned to, absent when it was unassigned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    assignee: Option<String>,
+}
+
+impl Activity {
+    pub fn new(actor: &str, action: TaskAction) -> Activity {
+        Activity { actor: actor.to_string(), action, at: db::now_millis(), fields: Vec::new(), assignee: None }
+    }
+
+    pub fn fields(mut self, fields: &[&str]) -> Activity {
+        self.fields = fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+
+    pub fn assignee(mut self, assignee: Option<&str>) -> Activity {
+        self.assignee = assignee.map(str::to_string);
+        self
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Comment {
+    /// The AE that wrote the comment.
+    author: String,
+    body: String,
+    /// Unix millis.
+    created_at: u64,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CreateCommentRB<'r> {
+    body: &'r str,
+}
+
+#[derive(serde::Serialize)]
+pub struct CommentPage {
+    comments: Vec<Comment>,
+    /// Continues with later comments, absent once there are none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+fn history_partition(owner: &str) -> String {
+    format!["{}#history", owner]
+}
+
+fn entry_prefix(task_id: &str, entry: &str) -> String {
+    format!["{}#{}#", task_id, entry]
+}
+
+/// Stores `value` as an entry of the task, keyed to sort after the task's earlier entries of the same kind.
+fn entry_item(owner: &str, task_id: &str, entry: &str, value: &impl serde::Serialize) -> Result<HashMap<String, AttributeValue>, ApiError> {
+    let mut item: HashMap<String, AttributeValue> = serde_dynamo::to_item(value)?;
+    let id = format!["{}{:013}-{}", entry_prefix(task_id, entry), db::now_millis(), Uuid::new_v4()];
+
+    item.insert("owner".to_string(), AttributeValue::S(history_partition(owner)));
+    item.insert("id".to_string(), AttributeValue::S(id));
+    item.insert("entry".to_string(), AttributeValue::S(entry.to_string()));
+    item.insert("task_id".to_string(), AttributeValue::S(task_id.to_string()));
+
+    Ok(item)
+}
+
+/// Appends to the history of the given tasks. The tasks are already written, so failures are only logged.
+pub async fn record(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, activities: Vec<(String, Activity)>) {
+    let table = db::Table::Tasker.as_str();
+
+    let mut requests = Vec::with_capacity(activities.len());
+
+    for (task_id, activity) in &activities {
+        match entry_item(owner, task_id, ACTIVITY_ENTRY, activity) {
+            Ok(item) => requests.push(WriteRequest::builder().put_request(PutRequest::builder().set_item(Some(item)).build()).build()),
+            Err(err) => tracing::error!(parent: &**span, error = %err, task_id = %task_id, "failed to convert task activity"),
+        }
+    }
+
+    for chunk in requests.chunks(db::BATCH_WRITE_CHUNK) {
+        let write = db_client.batch_write_item()
+            .request_items(table, chunk.to_vec())
+            .send();
+
+        let written = metrics::observe("dynamodb", "BatchWriteItem", write)
+            .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "BatchWriteItem", table = table))
+            .await;
+
+        let unwritten = match written {
+            Ok(output) => output.unprocessed_items.and_then(|mut unprocessed| unprocessed.remove(table)).map_or(0, |requests| requests.len()),
+            Err(err) => {
+                tracing::error!(parent: &**span, error = %ApiError::from(err), "failed to record task activity");
+                chunk.len()
+            },
+        };
+
+        if unwritten > 0 {
+            tracing::error!(parent: &**span, owner = %owner, count = unwritten, "lost task activity");
+        }
+    }
+}
+
+/// One page of a task's entries of one kind, oldest first.
+async fn entries<T: serde::de::DeserializeOwned>(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, task_id: &str, entry: &str, limit: Option<i32>, start: Option<HashMap<String, AttributeValue>>) -> Result<(Vec<T>, Option<HashMap<String, AttributeValue>>), ApiError> {
+    let query = db_client.query()
+        .table_name(db::Table::Tasker.as_str())
+        .key_condition_expression("#owner = :owner AND begins_with(id, :prefix)")
+        .expression_attribute_names("#owner", "owner")
+        .expression_attribute_values(":owner", AttributeValue::S(history_partition(owner)))
+        .expression_attribute_values(":prefix", AttributeValue::S(entry_prefix(task_id, entry)))
+        .set_limit(limit)
+        .set_exclusive_start_key(start)
+        .send();
+
+    let result = metrics::observe("dynamodb", "Query", query)
+        .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "Query", table = db::Table::Tasker.as_str()))
+        .await?;
+
+    Ok((serde_dynamo::from_items(result.items.unwrap_or_default())?, result.last_evaluated_key))
+}
+
+/// The whole history of a task, oldest first.
+pub async fn activity_of(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, task_id: &str) -> Result<Vec<Activity>, ApiError> {
+    let mut activity = Vec::new();
+    let mut start = None;
+
+    loop {
+        let (page, next) = entries::<Activity>(db_client, span, owner, task_id, ACTIVITY_ENTRY, None, start).await?;
+        activity.extend(page);
+
+        match next {
+            Some(next) => start = Some(next),
+            None => return Ok(activity),
+        }
+    }
+}
+
+/// Comments on the task by the calling AE.
+#[rocket::post("/<entity>/task/<id>/comment", data = "<comment>")]
+pub async fn create_comment(ae: Authorized<TaskListWrite>, span: RequestSpan, comment: Json<CreateCommentRB<'_>>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str, id: &str) -> ApiReturnValue<Comment> {
+    if comment.body.trim().is_empty() || comment.body.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(ApiError::InvalidComment);
+    }
+
+    tasker::find_task(db_client, &span, entity, id).await?;
+
+    let comment = Comment {
+        author: ae.id.clone(),
+        body: comment.body.to_string(),
+        created_at: db::now_millis(),
+    };
+
+    let put = db_client.put_item()
+        .table_name(db::Table::Tasker.as_str())
+        .set_item(Some(entry_item(entity, id, COMMENT_ENTRY, &comment)?))
+        .send();
+
+    metrics::observe("dynamodb", "PutItem", put)
+        .instrument(tracing::info_span!(parent: &*span, "dynamodb", operation = "PutItem", table = db::Table::Tasker.as_str()))
+        .await?;
+
+    tracing::info!(parent: &*span, entity_id = %ae.id, owner = %entity, task_id = %id, "commented on task");
+
+    Ok(ApiResponse(Json(comment)))
+}
+
+/// The task's comments, oldest first.
+#[rocket::get("/<entity>/task/<id>/comment?<limit>&<cursor>")]
+pub async fn get_comments(_ae: Authorized<TaskListRead>, span: RequestSpan, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str, id: &str, limit: Option<i32>, cursor: Option<&str>) -> ApiReturnValue<CommentPage> {
+    let start = cursor.map(|cursor| db::decode_cursor(cursor).ok_or(ApiError::InvalidQueryParameter)).transpose()?;
+
+    tasker::find_task(db_client, &span, entity, id).await?;
+
+    let (comments, next) = entries(db_client, &span, entity, id, COMMENT_ENTRY, Some(db::page_size(limit)), start).await?;
+
+    Ok(ApiResponse(Json(CommentPage { comments, next_cursor: next.as_ref().map(db::encode_cursor) })))
+}
+
+guarded_routes![create_comment, get_comments];
+
+#[cfg(test)]
+mod task_history_tests {
+    use super::*;
+
+    #[test]
+    fn entries_sort_after_their_task_by_kind_and_time() {
+        let item = entry_item("nate", "task", ACTIVITY_ENTRY, &Activity::new("nate", TaskAction::Edited).fields(&["description"])).unwrap();
+        let id = item["id"].as_s().unwrap();
+
+        assert!(id.starts_with("task#activity#"));
+        assert!(id.as_str() > "task");
+        assert_eq!(item["owner"].as_s().unwrap(), "nate#history");
+        assert_eq!(item["entry"].as_s().unwrap(), ACTIVITY_ENTRY);
+        assert_eq!(item["fields"].as_l().unwrap().len(), 1);
+    }
+}
@@ -1,21 +1,62 @@
-use aws_sdk_dynamodb::model::{AttributeValue, Select};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use aws_sdk_dynamodb::error::{TransactWriteItemsError, TransactWriteItemsErrorKind};
+use aws_sdk_dynamodb::model::{AttributeValue, CancellationReason, Delete, Put, PutRequest, ReturnValue, Select, TransactWriteItem, Update, WriteRequest};
+use aws_sdk_dynamodb::types::SdkError;
+use rocket::Shutdown;
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::Json;
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
-use crate::api_response::{ApiEmptyReturnValue, ApiError, ApiResponse, ApiReturnValue};
+use crate::api_response::{ApiError, ApiResponse, ApiReturnValue, ApiVersionedResponse, ApiVersionedReturnValue, ErrorKind};
+use crate::versioning::{self, IfMatch};
+use crate::config::AppConfig;
+use crate::idempotency::{Claim, IdempotencyKey};
 use crate::db;
-use crate::public::iam::AuthenticatableEntity;
+use crate::metrics;
+use crate::task_events::{self, Notification, TaskChange, TaskEvent, TaskEvents};
+use crate::telemetry::RequestSpan;
+use crate::webhooks;
+use crate::private::task_history::{self, Activity, TaskAction};
+use crate::public::iam::{is_valid_entity_id, SessionKey};
+use tracing::Instrument;
+use crate::authorization::{guarded_routes, Authorized, TaskListRead, TaskListWrite};
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, serde::Serialize)]
 pub struct CreateTaskRB<'r> {
     description: &'r str,
 }
 
+#[derive(serde::Deserialize)]
+pub struct UpdateTaskRB<'r> {
+    description: Option<&'r str>,
+    completed: Option<bool>,
+    /// An AE id, or `null` to unassign. Left alone when absent.
+    #[serde(default, deserialize_with = "present")]
+    assignee: Option<Option<String>>,
+}
+
+/// Tells a field set to `null` (`Some(None)`) apart from an absent one (`None`, with `#[serde(default)]`).
+fn present<'de, D: serde::Deserializer<'de>, T: serde::Deserialize<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct Task {
+    /// The entity whose list the task is on, i.e. the `<entity>` of the route it was created through. This is
+    /// not necessarily the creating AE, holding `nys:tasker:bob:TaskList:Write` lets any AE add to bob's list.
     owner: String,
     id: String,
     description: String,
     completed: bool,
+
+    /// The AE the task is assigned to. Not checked to exist, and grants it nothing on the list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    assignee: Option<String>,
+
+    /// Bumped on every write, see `versioning`.
+    #[serde(default)]
+    version: u64,
 }
 
 #[derive(serde::Serialize)]
@@ -23,62 +64,668 @@ pub struct TaskList {
     tasks: Vec<Task>,
 }
 
+#[derive(serde::Serialize)]
+pub struct TaskDetail {
+    #[serde(flatten)]
+    task: Task,
+    /// Oldest first.
+    activity: Vec<Activity>,
+}
+
+/// Task ids are generated UUIDs. A `#` would address one of the task's entries, see `task_history`.
+fn is_task_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains('#')
+}
+
+/// Condition for writes to a task that must exist.
+const EXISTING_TASK: &str = "attribute_exists(id)";
+
+/// The most operations per batch, which is also the most `TransactWriteItems` accepts.
+const MAX_BATCH_OPERATIONS: usize = 100;
+
+/// How often event streams check that their session and grant are still valid.
+const STREAM_REAUTHORIZATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often writes DynamoDB left unprocessed are retried before they count as failed.
+const UNPROCESSED_RETRIES: u32 = 3;
+
+/// Marks a completion and bumps the version, see `versioning`. Items from before versioning start over at 1.
+const COMPLETE_EXPRESSION: &str = "SET completed = :completed, #version = if_not_exists(#version, :zero) + :one";
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+    Create { description: String },
+    Complete { id: String },
+    Delete { id: String },
+}
+
+#[derive(serde::Deserialize)]
+pub struct BatchRB {
+    /// Apply every operation or none. Otherwise each operation succeeds or fails on its own.
+    #[serde(default)]
+    transactional: bool,
+    operations: Vec<BatchOperation>,
+}
+
+#[derive(serde::Serialize)]
+pub struct BatchItemError {
+    code: u8,
+    message: &'static str,
+}
+
+#[derive(serde::Serialize)]
+pub struct BatchItemResult {
+    /// Position of the operation in the request.
+    index: usize,
+    id: String,
+    ok: bool,
+
+    /// The task as written, where known. Deletes and transactional completions only report the id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task: Option<Task>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<BatchItemError>,
+}
+
+#[derive(serde::Serialize)]
+pub struct BatchResult {
+    transactional: bool,
+    results: Vec<BatchItemResult>,
+}
+
+/// An operation bound to the task it affects, creations get their id up front.
+struct PlannedOperation {
+    index: usize,
+    id: String,
+    kind: PlannedKind,
+}
+
+enum PlannedKind {
+    Create(Task),
+    Complete,
+    Delete,
+}
+
+impl PlannedOperation {
+    fn succeeded(&self, task: Option<Task>) -> BatchItemResult {
+        let task = match &self.kind {
+            PlannedKind::Create(created) => Some(created.clone()),
+            _ => task,
+        };
+
+        BatchItemResult { index: self.index, id: self.id.clone(), ok: true, task, error: None }
+    }
+
+    fn failed(&self, kind: ErrorKind) -> BatchItemResult {
+        BatchItemResult {
+            index: self.index,
+            id: self.id.clone(),
+            ok: false,
+            task: None,
+            error: Some(BatchItemError { code: kind.code(), message: kind.name() }),
+        }
+    }
+
+    fn action(&self) -> TaskAction {
+        match self.kind {
+            PlannedKind::Create(_) => TaskAction::Created,
+            PlannedKind::Complete => TaskAction::Completed,
+            PlannedKind::Delete => TaskAction::Deleted,
+        }
+    }
+
+    fn change(&self) -> TaskChange {
+        match self.kind {
+            PlannedKind::Create(_) => TaskChange::Created,
+            PlannedKind::Complete => TaskChange::Completed,
+            PlannedKind::Delete => TaskChange::Deleted,
+        }
+    }
+
+    fn key(&self, owner: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            ("owner".to_string(), AttributeValue::S(owner.to_string())),
+            ("id".to_string(), AttributeValue::S(self.id.clone())),
+        ])
+    }
+}
+
+/// Assigns ids and rejects batches DynamoDB would refuse: empty, too large, or touching a task twice.
+/// Also rejects ids that can't be tasks, which would otherwise reach the task's history entries.
+fn plan(owner: &str, operations: Vec<BatchOperation>) -> Result<Vec<PlannedOperation>, ApiError> {
+    if operations.is_empty() || operations.len() > MAX_BATCH_OPERATIONS {
+        return Err(ApiError::InvalidBatch);
+    }
+
+    let planned: Vec<PlannedOperation> = operations.into_iter().enumerate().map(|(index, operation)| match operation {
+        BatchOperation::Create { description } => {
+            let task = Task { owner: owner.to_string(), id: Uuid::new_v4().to_string(), description, completed: false, assignee: None, version: 1 };
+            PlannedOperation { index, id: task.id.clone(), kind: PlannedKind::Create(task) }
+        },
+        BatchOperation::Complete { id } => PlannedOperation { index, id, kind: PlannedKind::Complete },
+        BatchOperation::Delete { id } => PlannedOperation { index, id, kind: PlannedKind::Delete },
+    }).collect();
+
+    let mut seen = HashSet::new();
+
+    if !planned.iter().all(|operation| is_task_id(&operation.id) && seen.insert(operation.id.as_str())) {
+        return Err(ApiError::InvalidBatch);
+    }
+
+    Ok(planned)
+}
+
+/// Tells live streams and webhook subscribers about changes that were written.
+fn announce(redis_client: &redis::Client, events: &[TaskEvent]) {
+    task_events::publish(redis_client, events);
+    webhooks::dispatch(events);
+}
+
+/// Adds a task to the entity's list. The creating AE is recorded in the task's activity, not as its owner.
 #[rocket::post("/<entity>/task", data = "<task>")]
-pub async fn create_task(ae: AuthenticatableEntity, task: Json<CreateTaskRB<'_>>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str) -> ApiReturnValue<Task> {
-    ae.assert_privilege(format!["nys:tasker:{}:TaskList:Write", entity])?;
+#[allow(clippy::too_many_arguments)]
+pub async fn create_task(ae: Authorized<TaskListWrite>, span: RequestSpan, idempotency_key: IdempotencyKey, task: Json<CreateTaskRB<'_>>, config: &rocket::State<AppConfig>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, entity: &str) -> ApiVersionedReturnValue<Task> {
+    // Retries by the same AE for the same list replay the task created first
+    let scope = format!["create_task:{}:{}", ae.id, entity];
+
+    let reservation = match idempotency_key.claim::<Task>(redis_client, &config.idempotency, &scope, &*task)? {
+        Claim::Replay(task) => return Ok(ApiVersionedResponse::new(task.version, task)),
+        Claim::Proceed(reservation) => reservation,
+    };
 
+    let created = insert_task(db_client, &span, entity, task.description).await;
+    reservation.settle(redis_client, &created);
+    let new_task = created?;
+
+    tracing::info!(parent: &*span, entity_id = %ae.id, task_id = %new_task.id, owner = %entity, "created task");
+    task_history::record(db_client, &span, entity, vec![(new_task.id.clone(), Activity::new(&ae.id, TaskAction::Created))]).await;
+    announce(redis_client, &[TaskEvent::new(TaskChange::Created, entity, &new_task.id, Some(new_task.clone()))]);
+
+    Ok(ApiVersionedResponse::new(new_task.version, new_task))
+}
+
+async fn insert_task(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, description: &str) -> Result<Task, ApiError> {
     let new_task = Task {
-        owner: ae.id,
+        owner: owner.to_string(),
         id: Uuid::new_v4().to_string(),
-        description: task.description.to_string(),
+        description: description.to_string(),
         completed: false,
+        assignee: None,
+        version: 1,
     };
 
-    let item = serde_dynamo::to_item(new_task.clone()).unwrap();
+    let item = serde_dynamo::to_item(new_task.clone())?;
 
-    db_client.put_item()
-        .table_name("NYS_tasker")
+    let put = db_client.put_item()
+        .table_name(db::Table::Tasker.as_str())
         .set_item(Some(item))
-        .send().await;
+        .send();
+
+    metrics::observe("dynamodb", "PutItem", put)
+        .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "PutItem", table = db::Table::Tasker.as_str()))
+        .await?;
 
-    Ok(ApiResponse(Json(new_task)))
+    Ok(new_task)
 }
 
-#[rocket::get("/<entity>/task/all")]
-pub async fn get_all_tasks(ae: AuthenticatableEntity, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str) -> ApiReturnValue<TaskList> {
-    ae.assert_privilege(format!["nys:tasker:{}:TaskList:Read", entity])?;
-
-    let query = db_client.query()
-        .table_name(db::Table::TASKER.as_str())
-        .key_condition_expression("#owner = :owner")
-        .expression_attribute_names("#owner", "owner")
-        .expression_attribute_values(":owner", AttributeValue::S(ae.id))
-        .select(Select::AllAttributes)
-        .send().await;
-
-    let query_result = match query {
-        Ok(res) => res,
-        Err(_) => return Err(ApiError::MeNoLikeyAWS),
+/// Changes only the given fields. With `If-Match`, fails with 412 unless the task is still at that version.
+#[rocket::patch("/<entity>/task/<id>", data = "<changes>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_task(ae: Authorized<TaskListWrite>, span: RequestSpan, if_match: IfMatch, changes: Json<UpdateTaskRB<'_>>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, entity: &str, id: &str) -> ApiVersionedReturnValue<Task> {
+    let mut task = find_task(db_client, &span, entity, id).await?;
+
+    if_match.check(task.version)?;
+
+    let expected_version = task.version;
+    let was_completed = task.completed;
+    let previous_assignee = task.assignee.clone();
+    let mut edited_fields = Vec::new();
+
+    if let Some(description) = changes.description {
+        if description != task.description {
+            edited_fields.push("description");
+        }
+
+        task.description = description.to_string();
+    }
+
+    if let Some(completed) = changes.completed {
+        task.completed = completed;
+    }
+
+    if let Some(assignee) = &changes.assignee {
+        if assignee.as_deref().is_some_and(|assignee| !is_valid_entity_id(assignee)) {
+            return Err(ApiError::InvalidEntityId);
+        }
+
+        task.assignee = assignee.clone();
+    }
+
+    task.version += 1;
+
+    // Someone else may have written the task since it was read above
+    let put = db_client.put_item()
+        .table_name(db::Table::Tasker.as_str())
+        .set_item(Some(serde_dynamo::to_item(task.clone())?));
+
+    metrics::observe("dynamodb", "PutItem", versioning::expect_version(put, expected_version).send())
+        .instrument(tracing::info_span!(parent: &*span, "dynamodb", operation = "PutItem", table = db::Table::Tasker.as_str()))
+        .await
+        .map_err(|err| versioning::conflict_or(err, ApiError::PreconditionFailed))?;
+
+    tracing::info!(parent: &*span, entity_id = %ae.id, task_id = %task.id, owner = %entity, version = task.version, "updated task");
+    let change = if task.completed && !was_completed { TaskChange::Completed } else { TaskChange::Updated };
+    announce(redis_client, &[TaskEvent::new(change, entity, &task.id, Some(task.clone()))]);
+
+    let mut activity = Vec::new();
+
+    if !edited_fields.is_empty() {
+        activity.push((task.id.clone(), Activity::new(&ae.id, TaskAction::Edited).fields(&edited_fields)));
+    }
+
+    match (was_completed, task.completed) {
+        (false, true) => activity.push((task.id.clone(), Activity::new(&ae.id, TaskAction::Completed))),
+        (true, false) => activity.push((task.id.clone(), Activity::new(&ae.id, TaskAction::Reopened))),
+        _ => {},
+    }
+
+    if task.assignee != previous_assignee {
+        activity.push((task.id.clone(), Activity::new(&ae.id, TaskAction::Reassigned).assignee(task.assignee.as_deref())));
+    }
+
+    task_history::record(db_client, &span, entity, activity).await;
+
+    Ok(ApiVersionedResponse::new(task.version, task))
+}
+
+/// Creates, completes and deletes tasks of one list. Results come back per operation, in request order.
+/// Completing or deleting a task that doesn't exist fails the operation, and in a transaction the whole batch.
+#[rocket::post("/<entity>/task/batch", data = "<batch>")]
+pub async fn batch_tasks(ae: Authorized<TaskListWrite>, span: RequestSpan, batch: Json<BatchRB>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, entity: &str) -> ApiReturnValue<BatchResult> {
+    let BatchRB { transactional, operations } = batch.into_inner();
+    let planned = plan(entity, operations)?;
+
+    let results = if transactional {
+        run_transaction(db_client, &span, entity, &planned).await?
+    } else {
+        run_individually(db_client, &span, entity, &planned).await?
     };
 
-    // And deserialize them as strongly-typed data structures
-    if let Some(items) = query_result.items {
-        let tasks: Vec<Task> = serde_dynamo::from_items(items).unwrap();
-        println!("Got {} tasks", tasks.len());
+    let failed = results.iter().filter(|result| !result.ok).count();
+    tracing::info!(parent: &*span, entity_id = %ae.id, owner = %entity, transactional, count = results.len(), failed, "ran task batch");
+
+    // Results are in the same order as the planned operations
+    let events: Vec<TaskEvent> = planned.iter().zip(&results)
+        .filter(|(_, result)| result.ok)
+        .map(|(operation, result)| TaskEvent::new(operation.change(), entity, &result.id, result.task.clone()))
+        .collect();
+    announce(redis_client, &events);
+
+    let activity = planned.iter().zip(&results)
+        .filter(|(_, result)| result.ok)
+        .map(|(operation, result)| (result.id.clone(), Activity::new(&ae.id, operation.action())))
+        .collect();
+    task_history::record(db_client, &span, entity, activity).await;
+
+    Ok(ApiResponse(Json(BatchResult { transactional, results })))
+}
+
+async fn run_transaction(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, planned: &[PlannedOperation]) -> Result<Vec<BatchItemResult>, ApiError> {
+    let mut transaction = db_client.transact_write_items();
+
+    for operation in planned {
+        transaction = transaction.transact_items(transact_item(owner, operation)?);
+    }
+
+    let written = metrics::observe("dynamodb", "TransactWriteItems", transaction.send())
+        .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "TransactWriteItems", table = db::Table::Tasker.as_str()))
+        .await;
+
+    match written {
+        Ok(_) => Ok(planned.iter().map(|operation| operation.succeeded(None)).collect()),
+        Err(SdkError::ServiceError { err: TransactWriteItemsError { kind: TransactWriteItemsErrorKind::TransactionCanceledException(cancelled), .. }, .. }) => {
+            // Reasons line up with the operations, the ones that didn't cause the cancellation report "None"
+            let reasons = cancelled.cancellation_reasons().unwrap_or_default();
 
-        return Ok(ApiResponse(Json(TaskList { tasks })));
+            Ok(planned.iter().enumerate()
+                .map(|(position, operation)| operation.failed(cancellation_error(reasons.get(position))))
+                .collect())
+        },
+        Err(err) => Err(err.into()),
     }
+}
+
+fn transact_item(owner: &str, operation: &PlannedOperation) -> Result<TransactWriteItem, ApiError> {
+    let table = db::Table::Tasker.as_str();
+
+    let item = match &operation.kind {
+        PlannedKind::Create(task) => TransactWriteItem::builder().put(Put::builder()
+            .table_name(table)
+            .set_item(Some(serde_dynamo::to_item(task.clone())?))
+            .condition_expression("attribute_not_exists(id)")
+            .build()),
+        PlannedKind::Complete => TransactWriteItem::builder().update(Update::builder()
+            .table_name(table)
+            .set_key(Some(operation.key(owner)))
+            .update_expression(COMPLETE_EXPRESSION)
+            .condition_expression(EXISTING_TASK)
+            .expression_attribute_names("#version", "version")
+            .set_expression_attribute_values(Some(complete_values()))
+            .build()),
+        PlannedKind::Delete => TransactWriteItem::builder().delete(Delete::builder()
+            .table_name(table)
+            .set_key(Some(operation.key(owner)))
+            .condition_expression(EXISTING_TASK)
+            .build()),
+    };
+
+    Ok(item.build())
+}
 
-    Err(ApiError::MeNoLikeyAWS)
+fn complete_values() -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (":completed".to_string(), AttributeValue::Bool(true)),
+        (":zero".to_string(), AttributeValue::N("0".to_string())),
+        (":one".to_string(), AttributeValue::N("1".to_string())),
+    ])
 }
 
-/*
-#[rocket::get("/task/<id>")]
-pub async fn get_task(id: &str, db_client: &rocket::State<aws_sdk_dynamodb::Client>) -> Json<Task<'_>> {
+fn cancellation_error(reason: Option<&CancellationReason>) -> ErrorKind {
+    match reason.and_then(|reason| reason.code()) {
+        // Creations can't collide on a fresh id, so a failed condition means the task is gone
+        Some("ConditionalCheckFailed") => ErrorKind::ResourceNotFound,
+        Some("TransactionConflict") => ErrorKind::PreconditionFailed,
+        Some("None") | None => ErrorKind::BatchAborted,
+        Some(_) => ErrorKind::MeNoLikeyAWS,
+    }
+}
+
+/// Creations go through `BatchWriteItem`. It can't update, nor make a delete conditional on the task existing,
+/// so completions and deletions are written one by one.
+async fn run_individually(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, planned: &[PlannedOperation]) -> Result<Vec<BatchItemResult>, ApiError> {
+    let mut results = Vec::with_capacity(planned.len());
+    let mut unwritten = HashSet::new();
+
+    let creations: Vec<&Task> = planned.iter()
+        .filter_map(|operation| match &operation.kind {
+            PlannedKind::Create(task) => Some(task),
+            _ => None,
+        })
+        .collect();
+
+    for chunk in creations.chunks(db::BATCH_WRITE_CHUNK) {
+        unwritten.extend(write_chunk(db_client, span, chunk).await?);
+    }
+
+    for operation in planned {
+        let written = match operation.kind {
+            PlannedKind::Create(_) if unwritten.contains(&operation.id) => {
+                results.push(operation.failed(ErrorKind::MeNoLikeyAWS));
+                continue;
+            },
+            PlannedKind::Create(_) => Ok(None),
+            PlannedKind::Complete => complete_task(db_client, span, owner, operation).await.map(Some),
+            PlannedKind::Delete => delete_task(db_client, span, owner, operation).await.map(|()| None),
+        };
+
+        let result = match written {
+            Ok(task) => operation.succeeded(task),
+            Err(err) => {
+                if let Some(cause) = err.cause() {
+                    tracing::error!(parent: &**span, error = %cause, task_id = %operation.id, "failed to write task in batch");
+                }
+
+                operation.failed(err.kind())
+            },
+        };
+
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+/// Writes up to `db::BATCH_WRITE_CHUNK` new tasks, returning the ids that could not be written.
+async fn write_chunk(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, chunk: &[&Task]) -> Result<Vec<String>, ApiError> {
+    let table = db::Table::Tasker.as_str();
+
+    let mut pending = chunk.iter()
+        .map(|task| write_request(task))
+        .collect::<Result<Vec<WriteRequest>, ApiError>>()?;
+
+    for attempt in 0..=UNPROCESSED_RETRIES {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_millis(50 << attempt)).await;
+        }
+
+        let write = db_client.batch_write_item()
+            .request_items(table, pending.clone())
+            .send();
+
+        let written = metrics::observe("dynamodb", "BatchWriteItem", write)
+            .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "BatchWriteItem", table = table))
+            .await;
+
+        match written {
+            Ok(output) => pending = output.unprocessed_items.and_then(|mut unprocessed| unprocessed.remove(table)).unwrap_or_default(),
+            Err(err) => {
+                tracing::error!(parent: &**span, error = %ApiError::from(err), "task batch write failed");
+                break;
+            },
+        }
+
+        if pending.is_empty() {
+            break;
+        }
+    }
+
+    Ok(pending.iter().filter_map(written_id).collect())
+}
+
+fn write_request(task: &Task) -> Result<WriteRequest, ApiError> {
+    Ok(WriteRequest::builder()
+        .put_request(PutRequest::builder().set_item(Some(serde_dynamo::to_item(task.clone())?)).build())
+        .build())
+}
+
+fn written_id(request: &WriteRequest) -> Option<String> {
+    request.put_request()?.item()?.get("id")?.as_s().ok().cloned()
+}
+
+async fn delete_task(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, operation: &PlannedOperation) -> Result<(), ApiError> {
+    let delete = db_client.delete_item()
+        .table_name(db::Table::Tasker.as_str())
+        .set_key(Some(operation.key(owner)))
+        .condition_expression(EXISTING_TASK)
+        .send();
+
+    metrics::observe("dynamodb", "DeleteItem", delete)
+        .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "DeleteItem", table = db::Table::Tasker.as_str()))
+        .await
+        .map_err(|err| match &err {
+            SdkError::ServiceError { err, .. } if err.is_conditional_check_failed_exception() => ApiError::ResourceNotFound,
+            _ => err.into(),
+        })?;
 
+    Ok(())
 }
-*/
 
-pub fn routes() -> Vec<rocket::Route> {
-    rocket::routes![create_task, get_all_tasks]
-}
\ No newline at end of file
+async fn complete_task(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, operation: &PlannedOperation) -> Result<Task, ApiError> {
+    let update = db_client.update_item()
+        .table_name(db::Table::Tasker.as_str())
+        .set_key(Some(operation.key(owner)))
+        .update_expression(COMPLETE_EXPRESSION)
+        .condition_expression(EXISTING_TASK)
+        .expression_attribute_names("#version", "version")
+        .set_expression_attribute_values(Some(complete_values()))
+        .return_values(ReturnValue::AllNew)
+        .send();
+
+    let updated = metrics::observe("dynamodb", "UpdateItem", update)
+        .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "UpdateItem", table = db::Table::Tasker.as_str()))
+        .await
+        .map_err(|err| match &err {
+            SdkError::ServiceError { err, .. } if err.is_conditional_check_failed_exception() => ApiError::ResourceNotFound,
+            _ => err.into(),
+        })?;
+
+    Ok(serde_dynamo::from_item(updated.attributes.unwrap_or_default())?)
+}
+
+/// Every task of the list, however many pages of results that takes.
+#[rocket::get("/<entity>/task/all")]
+pub async fn get_all_tasks(ae: Authorized<TaskListRead>, span: RequestSpan, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str) -> ApiReturnValue<TaskList> {
+    let mut tasks: Vec<Task> = Vec::new();
+    let mut start = None;
+
+    loop {
+        let query = db_client.query()
+            .table_name(db::Table::Tasker.as_str())
+            .key_condition_expression("#owner = :owner")
+            .expression_attribute_names("#owner", "owner")
+            .expression_attribute_values(":owner", AttributeValue::S(entity.to_string()))
+            .select(Select::AllAttributes)
+            .set_exclusive_start_key(start)
+            .send();
+
+        let query_result = metrics::observe("dynamodb", "Query", query)
+            .instrument(tracing::info_span!(parent: &*span, "dynamodb", operation = "Query", table = db::Table::Tasker.as_str()))
+            .await?;
+
+        // And deserialize them as strongly-typed data structures
+        tasks.extend(serde_dynamo::from_items::<_, Task>(query_result.items.unwrap_or_default())?);
+
+        match query_result.last_evaluated_key {
+            Some(next) => start = Some(next),
+            None => break,
+        }
+    }
+
+    tracing::info!(parent: &*span, entity_id = %ae.id, owner = %entity, count = tasks.len(), "listed tasks");
+
+    Ok(ApiResponse(Json(TaskList { tasks })))
+}
+
+/// Streams the list's changes as they happen, one `created`, `updated` or `deleted` SSE event per change.
+/// A `resync` event means changes may have been missed and the list should be fetched again.
+/// The stream ends once its session is revoked or the AE loses read access to the list.
+#[rocket::get("/<entity>/task/events")]
+#[allow(clippy::too_many_arguments)]
+pub fn stream_task_events(ae: Authorized<TaskListRead>, session_key: SessionKey, span: RequestSpan, events: &rocket::State<TaskEvents>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, mut shutdown: Shutdown, entity: &str) -> EventStream![Event + 'static] {
+    let owner = entity.to_string();
+    let mut receiver = events.subscribe();
+    let (db_client, redis_client) = (db_client.inner().clone(), redis_client.inner().clone());
+    let span = (*span).clone();
+
+    tracing::info!(parent: &span, entity_id = %ae.id, owner = %owner, "streaming task events");
+
+    EventStream! {
+        let mut reauthorization = tokio::time::interval_at(tokio::time::Instant::now() + STREAM_REAUTHORIZATION_INTERVAL, STREAM_REAUTHORIZATION_INTERVAL);
+
+        loop {
+            let notification = tokio::select! {
+                notification = receiver.recv() => notification,
+                _ = reauthorization.tick() => match ae.reauthorize(&db_client, &redis_client, &session_key).await {
+                    Ok(()) => continue,
+                    Err(err) => {
+                        tracing::info!(parent: &span, entity_id = %ae.id, owner = %owner, error = %err, "closed task event stream");
+                        break;
+                    },
+                },
+                _ = &mut shutdown => break,
+            };
+
+            match notification {
+                Ok(Notification::Change(event)) if event.owner == owner => {
+                    let event = event.streamed();
+                    yield Event::json(&event).event(event.change.name());
+                },
+                Ok(Notification::Change(_)) => continue,
+                Ok(Notification::Resync) | Err(RecvError::Lagged(_)) => yield Event::empty().event("resync"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// The task with its activity history. Ranked below the static `task/all` and `task/events` routes.
+#[rocket::get("/<entity>/task/<id>", rank = 2)]
+pub async fn get_task(_ae: Authorized<TaskListRead>, span: RequestSpan, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str, id: &str) -> ApiVersionedReturnValue<TaskDetail> {
+    let task = find_task(db_client, &span, entity, id).await?;
+    let activity = task_history::activity_of(db_client, &span, entity, id).await?;
+
+    Ok(ApiVersionedResponse::new(task.version, TaskDetail { task, activity }))
+}
+
+/// Reads a task consistently, `ResourceNotFound` if there is none under that id.
+pub async fn find_task(db_client: &aws_sdk_dynamodb::Client, span: &RequestSpan, owner: &str, id: &str) -> Result<Task, ApiError> {
+    if !is_task_id(id) {
+        return Err(ApiError::ResourceNotFound);
+    }
+
+    let get = db_client.get_item()
+        .table_name(db::Table::Tasker.as_str())
+        .key("owner", AttributeValue::S(owner.to_string()))
+        .key("id", AttributeValue::S(id.to_string()))
+        .consistent_read(true)
+        .send();
+
+    let stored = metrics::observe("dynamodb", "GetItem", get)
+        .instrument(tracing::info_span!(parent: &**span, "dynamodb", operation = "GetItem", table = db::Table::Tasker.as_str()))
+        .await?;
+
+    match stored.item {
+        Some(item) => Ok(serde_dynamo::from_item(item)?),
+        None => Err(ApiError::ResourceNotFound),
+    }
+}
+
+guarded_routes![create_task, update_task, batch_tasks, get_task, get_all_tasks, stream_task_events];
+
+#[cfg(test)]
+mod tasker_tests {
+    use super::*;
+
+    fn complete(id: &str) -> BatchOperation {
+        BatchOperation::Complete { id: id.to_string() }
+    }
+
+    #[test]
+    fn batches_are_bounded_and_touch_each_task_once() {
+        assert!(matches!(plan("nate", vec![]), Err(ApiError::InvalidBatch)));
+        assert!(matches!(plan("nate", (0..=MAX_BATCH_OPERATIONS).map(|i| complete(&i.to_string())).collect()), Err(ApiError::InvalidBatch)));
+        assert!(matches!(plan("nate", vec![complete("a"), BatchOperation::Delete { id: "a".to_string() }]), Err(ApiError::InvalidBatch)));
+
+        let planned = plan("nate", vec![BatchOperation::Create { description: "x".to_string() }, complete("a")]).unwrap();
+        assert_eq!(planned.iter().map(|operation| operation.index).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(matches!(&planned[0].kind, PlannedKind::Create(task) if task.id == planned[0].id && task.owner == "nate"));
+    }
+
+    #[test]
+    fn updates_tell_unassigning_from_leaving_the_assignee() {
+        let assignee = |body: &str| serde_json::from_str::<UpdateTaskRB>(body).unwrap().assignee;
+
+        assert_eq!(assignee(r#"{"completed":true}"#), None);
+        assert_eq!(assignee(r#"{"assignee":null}"#), Some(None));
+        assert_eq!(assignee(r#"{"assignee":"bob"}"#), Some(Some("bob".to_string())));
+    }
+
+    #[test]
+    fn batches_cannot_address_task_entries() {
+        assert!(matches!(plan("nate", vec![BatchOperation::Delete { id: "a#activity#0000000000000-x".to_string() }]), Err(ApiError::InvalidBatch)));
+        assert!(matches!(plan("nate", vec![complete("")]), Err(ApiError::InvalidBatch)));
+        assert!(plan("nate", vec![complete("a")]).is_ok());
+    }
+
+    #[test]
+    fn cancellation_reasons_map_to_error_kinds() {
+        let reason = |code: &str| CancellationReason::builder().code(code).build();
+
+        assert_eq!(cancellation_error(Some(&reason("ConditionalCheckFailed"))), ErrorKind::ResourceNotFound);
+        assert_eq!(cancellation_error(Some(&reason("None"))), ErrorKind::BatchAborted);
+        assert_eq!(cancellation_error(None), ErrorKind::BatchAborted);
+        assert_eq!(cancellation_error(Some(&reason("ThrottlingError"))), ErrorKind::MeNoLikeyAWS);
+    }
+}
@@ -0,0 +1,103 @@
+use rocket::serde::json::Json;
+use uuid::Uuid;
+use crate::api_response::{ApiEmptyReturnValue, ApiError, ApiResponse, ApiReturnValue};
+use crate::authorization::{guarded_routes, Authorized, WebhookRead, WebhookWrite};
+use crate::config::AppConfig;
+use crate::db;
+use crate::task_events::TaskChange;
+use crate::telemetry::RequestSpan;
+use crate::webhooks::{self, Delivery, DeliveryPage, Subscription, SubscriptionView};
+
+const MIN_SECRET_LENGTH: usize = 16;
+const MAX_SECRET_LENGTH: usize = 256;
+
+#[derive(serde::Deserialize)]
+pub struct CreateWebhookRB<'r> {
+    url: &'r str,
+    /// Signs every delivery, see `webhooks`.
+    secret: &'r str,
+    events: Vec<TaskChange>,
+}
+
+#[derive(serde::Serialize)]
+pub struct WebhookList {
+    webhooks: Vec<SubscriptionView>,
+}
+
+/// Subscribes `url` to the given changes of the entity's task list.
+#[rocket::post("/<entity>/webhook", data = "<webhook>")]
+pub async fn create_webhook(ae: Authorized<WebhookWrite>, span: RequestSpan, webhook: Json<CreateWebhookRB<'_>>, config: &rocket::State<AppConfig>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str) -> ApiReturnValue<SubscriptionView> {
+    let secret_length_valid = (MIN_SECRET_LENGTH..=MAX_SECRET_LENGTH).contains(&webhook.secret.len());
+
+    if !webhooks::is_valid_url(webhook.url, config.webhooks.allow_http) || !secret_length_valid || webhook.events.is_empty() {
+        return Err(ApiError::InvalidWebhook);
+    }
+
+    let mut events: Vec<TaskChange> = Vec::new();
+
+    for event in &webhook.events {
+        if !events.contains(event) {
+            events.push(*event);
+        }
+    }
+
+    let subscription = Subscription {
+        owner: entity.to_string(),
+        id: Uuid::new_v4().to_string(),
+        url: webhook.url.to_string(),
+        secret: webhook.secret.to_string(),
+        events,
+        created_at: db::now_millis() / 1000,
+    };
+
+    webhooks::save_subscription(db_client, &subscription).await?;
+    tracing::info!(parent: &*span, entity_id = %ae.id, owner = %entity, webhook_id = %subscription.id, "created webhook");
+
+    Ok(ApiResponse(Json(subscription.into())))
+}
+
+#[rocket::get("/<entity>/webhook/all")]
+pub async fn get_all_webhooks(_ae: Authorized<WebhookRead>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str) -> ApiReturnValue<WebhookList> {
+    let subscriptions = webhooks::list_subscriptions(db_client, entity).await?;
+
+    Ok(ApiResponse(Json(WebhookList { webhooks: subscriptions.into_iter().map(SubscriptionView::from).collect() })))
+}
+
+/// Stops future deliveries. The delivery log is kept, retries already scheduled still run.
+#[rocket::delete("/<entity>/webhook/<id>")]
+pub async fn delete_webhook(ae: Authorized<WebhookWrite>, span: RequestSpan, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str, id: &str) -> ApiEmptyReturnValue {
+    webhooks::delete_subscription(db_client, entity, id).await?;
+    tracing::info!(parent: &*span, entity_id = %ae.id, owner = %entity, webhook_id = %id, "deleted webhook");
+
+    Ok(())
+}
+
+/// The webhook's delivery log, newest first, with every attempt's status code or error.
+#[rocket::get("/<entity>/webhook/<id>/deliveries?<limit>&<cursor>")]
+pub async fn get_webhook_deliveries(_ae: Authorized<WebhookRead>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str, id: &str, limit: Option<i32>, cursor: Option<&str>) -> ApiReturnValue<DeliveryPage> {
+    // Deliveries are keyed by subscription alone, so ownership is checked on the subscription
+    webhooks::get_subscription(db_client, entity, id).await?;
+
+    let page = webhooks::list_deliveries(db_client, id, db::page_size(limit), cursor).await?;
+
+    Ok(ApiResponse(Json(page)))
+}
+
+/// Sends a logged delivery's payload again as a new delivery, with fresh retries.
+#[rocket::post("/<entity>/webhook/<id>/deliveries/<delivery>/redeliver")]
+pub async fn redeliver_webhook(ae: Authorized<WebhookWrite>, span: RequestSpan, db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity: &str, id: &str, delivery: &str) -> ApiReturnValue<Delivery> {
+    let subscription = webhooks::get_subscription(db_client, entity, id).await?;
+    let original = webhooks::get_delivery(db_client, id, delivery).await?;
+
+    let scheduled = webhooks::reserve()?;
+    let redelivery = original.redelivery();
+
+    webhooks::save_delivery(db_client, &redelivery).await?;
+    scheduled.deliver(subscription, redelivery.clone());
+
+    tracing::info!(parent: &*span, entity_id = %ae.id, owner = %entity, webhook_id = %id, delivery = %redelivery.id, redelivery_of = %original.id, "scheduled redelivery");
+
+    Ok(ApiResponse(Json(redelivery)))
+}
+
+guarded_routes![create_webhook, get_all_webhooks, delete_webhook, get_webhook_deliveries, redeliver_webhook];
@@ -1,12 +1,25 @@
 use aws_sdk_dynamodb::model::{AttributeValue, Select};
 use redis::Commands;
 use rocket::serde::json::Json;
-use rocket::http::{CookieJar, Cookie, Status};
+use rocket::http::{CookieJar, Cookie};
 use rocket::Request;
 use rocket::request::{FromRequest, Outcome};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
+use crate::audit::{self, AuditAction, AuditEvent, AuditOutcome};
+use crate::authorization::guarded_routes;
+use crate::config::AppConfig;
+use crate::request_id::RequestId;
+use crate::csrf;
 use crate::db;
-use crate::api_response::{ApiEmptyReturnValue, ApiError, ApiResponse, ApiReturnValue};
+use crate::metrics::{self, LoginOutcome};
+use crate::private::mfa::{MfaEnrollment, MFA_MAX_ATTEMPTS};
+use crate::telemetry::RequestSpan;
+use crate::versioning;
+use crate::idempotency::{Claim, IdempotencyKey};
+use tracing::Instrument;
+use crate::api_response::{guard_failure, ApiEmptyReturnValue, ApiError, ApiResponse, ApiReturnValue, ErrorCause};
 
 /*
 universe:service:entity:resource:action
@@ -17,109 +30,379 @@ pub struct AuthenticatedSession {
     entity_id: String,
 }
 
+/// Request attributes that grant conditions are evaluated against, and that audit events are attributed to.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub source_ip: Option<IpAddr>,
+    pub received_at: u64,
+    pub request_id: Option<String>,
+}
+
+impl RequestContext {
+    pub fn new(source_ip: Option<IpAddr>) -> RequestContext {
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or(0);
+
+        RequestContext {
+            source_ip,
+            received_at,
+            request_id: None,
+        }
+    }
+
+    pub fn of(req: &Request<'_>) -> RequestContext {
+        RequestContext {
+            request_id: Some(RequestId::of(req).to_string()),
+            ..RequestContext::new(client_address(req))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestContext {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestContext::of(req))
+    }
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        RequestContext::new(None)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+pub struct GrantConditions {
+    /// Unix timestamp (seconds) before which the grant is inactive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    valid_from: Option<u64>,
+
+    /// Unix timestamp (seconds) after which the grant is inactive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    valid_until: Option<u64>,
+
+    /// CIDR ranges (or single addresses) the request must originate from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_ranges: Option<Vec<String>>,
+}
+
+impl GrantConditions {
+    pub fn are_satisfied(&self, context: &RequestContext) -> bool {
+        if let Some(valid_from) = self.valid_from {
+            if context.received_at < valid_from {
+                return false;
+            }
+        }
+
+        if let Some(valid_until) = self.valid_until {
+            if context.received_at > valid_until {
+                return false;
+            }
+        }
+
+        if let Some(source_ranges) = &self.source_ranges {
+            let source_ip = match context.source_ip {
+                Some(ip) => ip,
+                None => return false,
+            };
+
+            if !source_ranges.iter().any(|range| ip_in_range(source_ip, range)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// A single permission grant. Plain strings are still accepted so existing entities keep working.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(untagged)]
+pub enum PermissionGrant {
+    Unconditional(String),
+    Conditional {
+        permission: String,
+        conditions: GrantConditions,
+    },
+}
+
+impl PermissionGrant {
+    pub fn permission(&self) -> &str {
+        match self {
+            PermissionGrant::Unconditional(permission) => permission,
+            PermissionGrant::Conditional { permission, .. } => permission,
+        }
+    }
+
+    pub fn applies_to(&self, context: &RequestContext) -> bool {
+        match self {
+            PermissionGrant::Unconditional(_) => true,
+            PermissionGrant::Conditional { conditions, .. } => conditions.are_satisfied(context),
+        }
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct PermissionsDefinition {
-    permissions: Vec<String>,
+    permissions: Vec<PermissionGrant>,
+}
+
+impl PermissionsDefinition {
+    /// Returns the first grant satisfying the requested privilege in the given context, or `None` if nothing matches.
+    pub fn matching_grant(&self, privilege: &str, context: &RequestContext) -> Result<Option<&PermissionGrant>, ApiError> {
+        let req_privilege_components: Vec<&str> = privilege.split_terminator(':').collect();
+
+        if req_privilege_components.len() != 5 {
+            return Err(ApiError::MalformedPermission);
+        }
+
+        for grant in &self.permissions {
+            let real_privilege_components: Vec<&str> = grant.permission().split_terminator(':').collect();
+
+            if real_privilege_components.len() != 5 {
+                continue;
+            }
+
+            let matches = real_privilege_components.iter()
+                .zip(&req_privilege_components)
+                .all(|(rcomp, pcomp)| privilege_component_matches(rcomp, pcomp));
+
+            if matches && grant.applies_to(context) {
+                return Ok(Some(grant));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// A granted component matches if it is identical, or if everything before its first `*` is a prefix of the requested one.
+///
+/// So `*` matches any component, `Task*` matches `Task` and `TaskList` but not `Tas`, and whatever follows the `*`
+/// is ignored (`Task*List` behaves like `Task*`). This replaces the character loop `assert_privilege` used to run,
+/// which was meant to do the same but had its comparison inverted: it dropped a grant at the first character that
+/// lined up, and let a component through when none did (`nate` granted `bill`).
+fn privilege_component_matches(rcomp: &str, pcomp: &str) -> bool {
+    match rcomp.find('*') {
+        Some(wildcard) => pcomp.starts_with(&rcomp[..wildcard]),
+        None => rcomp == pcomp,
+    }
+}
+
+/// The id ends up as the entity component of the AE's own grant, and as part of tasker sort keys. A wildcard
+/// would widen that grant to other AEs (`*` to all of them), a `:` would shift its components.
+pub(crate) fn is_valid_entity_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains(['*', ':', '#'])
+}
+
+/// The address a request came from. `X-Real-IP` is only believed on connections from one of the configured
+/// `trusted_proxies`, anyone else could put any address in it.
+pub(crate) fn client_address(req: &Request<'_>) -> Option<IpAddr> {
+    let trusted_proxies = req.rocket().state::<AppConfig>().map_or(&[][..], |config| &config.trusted_proxies[..]);
+
+    resolve_client_address(req.remote().map(|remote| remote.ip()), req.real_ip(), trusted_proxies)
+}
+
+fn resolve_client_address(peer: Option<IpAddr>, forwarded: Option<IpAddr>, trusted_proxies: &[String]) -> Option<IpAddr> {
+    match peer {
+        Some(peer) if trusted_proxies.iter().any(|range| ip_in_range(peer, range)) => forwarded.or(Some(peer)),
+        peer => peer,
+    }
+}
+
+/// Checks `ip` against a CIDR range such as `10.0.0.0/8`. A bare address matches only itself; malformed ranges match nothing.
+pub(crate) fn ip_in_range(ip: IpAddr, range: &str) -> bool {
+    let (network, prefix_len) = match range.split_once('/') {
+        Some((network, prefix_len)) => match prefix_len.parse::<u32>() {
+            Ok(prefix_len) => (network, Some(prefix_len)),
+            Err(_) => return false,
+        },
+        None => (range, None),
+    };
+
+    let network: IpAddr = match network.parse() {
+        Ok(network) => network,
+        Err(_) => return false,
+    };
+
+    match (ip.to_canonical(), network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let prefix_len = prefix_len.unwrap_or(32);
+            if prefix_len > 32 {
+                return false;
+            }
+
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let prefix_len = prefix_len.unwrap_or(128);
+            if prefix_len > 128 {
+                return false;
+            }
+
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct AuthenticatableEntity {
     pub id: String,
-    password_hash: String,
+
+    /// Only present when loaded from the primary store; never cached or returned to clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+
     enabled: bool,
     permissions: PermissionsDefinition,
+
+    /// Kept separately from `mfa` so cached copies still know a second factor is required.
+    #[serde(default)]
+    mfa_enabled: bool,
+
+    /// Only present when loaded from the primary store, like `password_hash`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mfa: Option<MfaEnrollment>,
+
+    #[serde(default)]
+    created_at: u64,
+
+    #[serde(default)]
+    updated_at: u64,
+
+    /// Bumped on every write, see `versioning`.
+    #[serde(default)]
+    version: u64,
+
+    #[serde(skip)]
+    context: RequestContext,
 }
 
 impl AuthenticatableEntity {
-    pub fn new(id: String, password: String) -> AuthenticatableEntity {
+    pub fn new(id: String, password: String) -> Result<AuthenticatableEntity, ApiError> {
+        if !is_valid_entity_id(&id) {
+            return Err(ApiError::InvalidEntityId);
+        }
+
         let base_permission_string = format!["nys:*:{}:*:*", id];
+        let now = RequestContext::default().received_at;
 
-        AuthenticatableEntity {
+        Ok(AuthenticatableEntity {
             id,
-            password_hash: bcrypt::hash(password, 10).unwrap(),
+            password_hash: Some(bcrypt::hash(password, 10)?),
             enabled: true,
             permissions: PermissionsDefinition {
-                permissions: Vec::from([base_permission_string]),
+                permissions: Vec::from([PermissionGrant::Unconditional(base_permission_string)]),
             },
-        }
+            mfa_enabled: false,
+            mfa: None,
+            created_at: now,
+            updated_at: now,
+            version: 1,
+            context: RequestContext::default(),
+        })
     }
 
-    pub fn assert_privilege(&self, privilege: String) -> Result<(), ApiError> {
-        let mut req_privilege_components = privilege.split_terminator(':');
-
-        if req_privilege_components.clone().count() != 5 {
-            return Err(ApiError::MalformedPermission);
+    pub fn view(&self) -> AuthenticatableEntityView {
+        AuthenticatableEntityView {
+            id: self.id.clone(),
+            enabled: self.enabled,
+            mfa_enabled: self.mfa_enabled,
+            permissions: self.permissions.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            version: self.version,
         }
+    }
 
-        'new_permission: for real_privilege in &self.permissions.permissions {
-            let mut real_privilege_components = real_privilege.split_terminator(':');
+    pub fn version(&self) -> u64 {
+        self.version
+    }
 
-            if real_privilege_components.clone().count() != 5 {
-                continue;
-            }
+    /// Checks a password against the stored hash. Entities without credential material never verify.
+    pub fn verify_password(&self, password: &str) -> bool {
+        match &self.password_hash {
+            Some(password_hash) => bcrypt::verify(password, password_hash).unwrap_or(false),
+            None => false,
+        }
+    }
 
-            'component: for i in 0..5 {
-                let pcomp = req_privilege_components.next().unwrap();
-                let rcomp = real_privilege_components.next().unwrap();
+    pub fn mfa_enabled(&self) -> bool {
+        self.mfa_enabled
+    }
 
-                if rcomp.len() > pcomp.len() {
-                    continue 'new_permission;
-                }
+    pub fn mfa(&self) -> Option<&MfaEnrollment> {
+        self.mfa.as_ref()
+    }
 
-                if rcomp.eq(pcomp) {
-                    continue 'component;
-                }
+    pub fn set_mfa(&mut self, mfa: Option<MfaEnrollment>) {
+        self.mfa_enabled = mfa.as_ref().is_some_and(|enrollment| enrollment.is_confirmed());
+        self.mfa = mfa;
+    }
 
-                'char: for y in 0..pcomp.len() {
-                    if rcomp.chars().nth(y).unwrap() == '*' {
-                        continue 'component;
-                    }
+    /// Removes everything that must not leave the primary store.
+    fn strip_credentials(&mut self) {
+        self.password_hash = None;
+        self.mfa = None;
+    }
 
-                    if rcomp.chars().nth(y).unwrap() != pcomp.chars().nth(y).unwrap() {
-                        continue 'char;
-                    }
+    pub fn permissions(&self) -> &PermissionsDefinition {
+        &self.permissions
+    }
 
-                    continue 'new_permission;
-                }
-            }
+    pub fn context(&self) -> &RequestContext {
+        &self.context
+    }
 
-            return Ok(());
+    pub fn assert_privilege(&self, privilege: String) -> Result<(), ApiError> {
+        match self.permissions.matching_grant(&privilege, &self.context)? {
+            Some(_) => Ok(()),
+            None => {
+                audit::record(AuditEvent::new(AuditAction::PrivilegeCheck, AuditOutcome::Denied, &self.id, &self.context).target(privilege));
+                Err(ApiError::NoMatchingPrivilege)
+            },
         }
-
-        Err(ApiError::NoMatchingPrivilege)
     }
 
-    pub async fn retrieve(db_client: &aws_sdk_dynamodb::Client, redis_client: &redis::Client, id: String, force_reload: bool) -> Result<AuthenticatableEntity, ApiError> {
-        let ae_cache_key = format!("cache:ae:{}", id);
+    /// Checks again that the session still belongs to this AE, and that the AE as currently stored still holds
+    /// `privilege`. For responses that outlive the request they were authorized for, like event streams.
+    pub async fn reauthorize(&self, db_client: &aws_sdk_dynamodb::Client, redis_client: &redis::Client, session_key: &SessionKey, privilege: &str) -> Result<(), ApiError> {
+        let session_cache_key = format!("session:{}", session_key.0);
 
-        // Start by checking the cache for the user
-        if !force_reload {
-            match redis_client.get_connection() {
-                Ok(mut conn) => {
-                    let ae_exists : bool = conn.exists(&ae_cache_key).unwrap();
-
-                    if ae_exists {
-                        // Get value string
-                        let ae_json : String = conn.get(&ae_cache_key).unwrap();
-                        return Ok(serde_json::from_str(&ae_json).unwrap());
-                    }
-                },
-                Err(_) => return Err(ApiError::CacheUnavailable),
-            }
+        let ae_id : Option<String> = metrics::observe_sync("redis", "GET", || {
+            redis_client.get_connection()?.get(&session_cache_key)
+        })?;
+
+        if ae_id.as_deref() != Some(self.id.as_str()) {
+            return Err(ApiError::InvalidSession);
         }
 
-        // Query entity from DB
+        // Saving an AE drops its cached copy, so this sees revoked grants
+        let mut current = AuthenticatableEntity::retrieve(db_client, redis_client, self.id.clone(), false).await?;
+        current.context = RequestContext { received_at: RequestContext::default().received_at, ..self.context.clone() };
+
+        current.assert_privilege(privilege.to_string())
+    }
+
+    /// Loads the AE, including its credential material, straight from the primary store.
+    pub async fn load(db_client: &aws_sdk_dynamodb::Client, id: String) -> Result<AuthenticatableEntity, ApiError> {
         let query = db_client.query()
-            .table_name(db::Table::IAM.as_str())
+            .table_name(db::Table::Iam.as_str())
             .key_condition_expression("id = :id")
             .expression_attribute_values(":id", AttributeValue::S(id))
             .select(Select::AllAttributes)
-            .send().await;
+            .send();
 
-        let query_result = match query {
-            Ok(res) => res,
-            Err(_) => return Err(ApiError::MeNoLikeyAWS),
-        };
+        let query_result = metrics::observe("dynamodb", "Query", query)
+            .instrument(tracing::info_span!("dynamodb", operation = "Query", table = db::Table::Iam.as_str()))
+            .await?;
 
         if query_result.count() > 1 {
             return Err(ApiError::TooManyUsers);
@@ -130,22 +413,114 @@ impl AuthenticatableEntity {
             None => return Err(ApiError::UserNotFound),
         };
 
-        let authenticatable_entities : Vec<AuthenticatableEntity> = serde_dynamo::from_items(query_items).unwrap();
-        let authenticatable_entity = match authenticatable_entities.first() {
-            Some(user) => user.clone(),
-            None => return Err(ApiError::UserNotFound),
-        };
+        let authenticatable_entities : Vec<AuthenticatableEntity> = serde_dynamo::from_items(query_items)?;
+        match authenticatable_entities.first() {
+            Some(user) => Ok(user.clone()),
+            None => Err(ApiError::UserNotFound),
+        }
+    }
 
-        // Cache AE
-        match redis_client.get_connection() {
-            Ok(mut conn) => {
-                let _ : () = conn.set(ae_cache_key, serde_json::to_string(&authenticatable_entity).unwrap()).unwrap();
-            },
-            Err(_) => return Err(ApiError::CacheUnavailable),
+    /// Retrieves the AE for authorization purposes, going through the cache. The result never carries credential material.
+    pub async fn retrieve(db_client: &aws_sdk_dynamodb::Client, redis_client: &redis::Client, id: String, force_reload: bool) -> Result<AuthenticatableEntity, ApiError> {
+        let ae_cache_key = format!("cache:ae:{}", id);
+
+        // Start by checking the cache for the user
+        if !force_reload {
+            let ae_json : Option<String> = tracing::info_span!("redis", command = "GET").in_scope(|| {
+                metrics::observe_sync("redis", "GET", || redis_client.get_connection()?.get(&ae_cache_key))
+            })?;
+
+            tracing::debug!(cache_hit = ae_json.is_some(), "AE cache lookup");
+            metrics::record_ae_cache_lookup(ae_json.is_some());
+
+            if let Some(ae_json) = ae_json {
+                let mut authenticatable_entity : AuthenticatableEntity = serde_json::from_str(&ae_json)?;
+                authenticatable_entity.strip_credentials();
+
+                return Ok(authenticatable_entity);
+            }
         }
 
+        let mut authenticatable_entity = AuthenticatableEntity::load(db_client, id).await?;
+        authenticatable_entity.strip_credentials();
+
+        // Cache AE
+        let ae_json = serde_json::to_string(&authenticatable_entity)?;
+        let _ : () = tracing::info_span!("redis", command = "SET").in_scope(|| {
+            metrics::observe_sync("redis", "SET", || redis_client.get_connection()?.set(ae_cache_key, ae_json))
+        })?;
+
         Ok(authenticatable_entity)
     }
+
+    /// Writes the AE back to the primary store and drops the cached copy. Must only be called on an AE obtained
+    /// through `load`, as a stripped AE would erase its own credentials. Fails with `PreconditionFailed` if the
+    /// AE was written by someone else since it was loaded.
+    pub async fn save(&mut self, db_client: &aws_sdk_dynamodb::Client, redis_client: &redis::Client) -> Result<(), ApiError> {
+        if self.password_hash.is_none() {
+            return Err(ApiError::Internal(ErrorCause::new(format!["Refusing to save AE {} without credentials", self.id])));
+        }
+
+        let expected_version = self.version;
+
+        self.updated_at = RequestContext::default().received_at;
+        self.version += 1;
+
+        let item = serde_dynamo::to_item(&*self)?;
+
+        let put = db_client.put_item()
+            .table_name(db::Table::Iam.as_str())
+            .set_item(Some(item));
+
+        let written = metrics::observe("dynamodb", "PutItem", versioning::expect_version(put, expected_version).send())
+            .instrument(tracing::info_span!("dynamodb", operation = "PutItem", table = db::Table::Iam.as_str()))
+            .await;
+
+        if let Err(err) = written {
+            self.version = expected_version;
+            return Err(versioning::conflict_or(err, ApiError::PreconditionFailed));
+        }
+
+        let _ : () = tracing::info_span!("redis", command = "DEL").in_scope(|| {
+            metrics::observe_sync("redis", "DEL", || redis_client.get_connection()?.del(format!("cache:ae:{}", self.id)))
+        })?;
+
+        Ok(())
+    }
+}
+
+/// The client-facing representation of an AE, free of credential material.
+#[derive(serde::Serialize)]
+pub struct AuthenticatableEntityView {
+    id: String,
+    enabled: bool,
+    mfa_enabled: bool,
+    permissions: PermissionsDefinition,
+    created_at: u64,
+    updated_at: u64,
+    version: u64,
+}
+
+/// The session key a request is authenticated with, from a bearer token or the `nys-session` cookie.
+pub struct SessionKey(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionKey {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // Bearer tokens take precedence, only the cookie is sent by browsers on their own
+        let bearer = req.headers().get_one("Authorization").and_then(|header| header.strip_prefix("Bearer "));
+
+        match (bearer, req.cookies().get("nys-session")) {
+            (Some(token), _) => Outcome::Success(SessionKey(token.trim().to_string())),
+            (None, Some(cookie)) => match csrf::verify(req) {
+                Ok(()) => Outcome::Success(SessionKey(cookie.value().to_string())),
+                Err(err) => guard_failure(req, err),
+            },
+            (None, None) => guard_failure(req, ApiError::MissingSessionKey),
+        }
+    }
 }
 
 #[rocket::async_trait]
@@ -153,47 +528,45 @@ impl<'r> FromRequest<'r> for AuthenticatableEntity {
     type Error = ApiError;
     
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        // Get session key from cookies
-        let session_cookie = req.cookies().get("nys-session");
-
-        let session_key = match session_cookie {
-            Some(cookie) => cookie.value().to_string(),
-            None => return Outcome::Failure((Status::BadRequest, ApiError::MissingSessionKey)),
+        let session_key = match req.guard::<SessionKey>().await {
+            Outcome::Success(SessionKey(session_key)) => session_key,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
         };
 
         // Grab database and cache clients
         let ddb_client = match req.rocket().state::<aws_sdk_dynamodb::Client>() {
             Some(client) => client,
-            None => return Outcome::Failure((Status::InternalServerError, ApiError::MeNoLikeyAWS)),
+            None => return guard_failure(req, ApiError::Internal(ErrorCause::new("DynamoDB client is not managed"))),
         };
 
         let redis_client = match req.rocket().state::<redis::Client>() {
             Some(client) => client,
-            None => return Outcome::Failure((Status::InternalServerError, ApiError::CacheUnavailable)),
+            None => return guard_failure(req, ApiError::Internal(ErrorCause::new("Redis client is not managed"))),
         };
 
         // Find AE associated with session
         let session_cache_key = format!("session:{}", session_key);
 
-        let ae_id : String;
-
-        match redis_client.get_connection() {
-            Ok(mut conn) => {
-                let session_exists : bool = conn.exists(&session_cache_key).unwrap();
+        let session_lookup : Result<Option<String>, redis::RedisError> = metrics::observe_sync("redis", "GET", || {
+            redis_client.get_connection()?.get(&session_cache_key)
+        });
 
-                if session_exists {
-                    // Get value string
-                    ae_id = conn.get(&session_cache_key).unwrap();
-                } else {
-                    return Outcome::Failure((Status::Unauthorized, ApiError::CacheUnavailable));
-                }
-            },
-            Err(_) => return Outcome::Failure((Status::InternalServerError, ApiError::CacheUnavailable)),
+        let ae_id = match session_lookup {
+            Ok(Some(ae_id)) => ae_id,
+            Ok(None) => return guard_failure(req, ApiError::InvalidSession),
+            Err(err) => return guard_failure(req, err.into()),
         };
 
-        match AuthenticatableEntity::retrieve(ddb_client, redis_client, ae_id, false).await {
-            Ok(ae) => Outcome::Success(ae),
-            Err(err) => Outcome::Failure((Status::InternalServerError, err)),
+        let span = RequestSpan::of(req);
+        span.record("entity_id", ae_id.as_str());
+
+        match AuthenticatableEntity::retrieve(ddb_client, redis_client, ae_id, false).instrument((**span).clone()).await {
+            Ok(mut ae) => {
+                ae.context = RequestContext::of(req);
+                Outcome::Success(ae)
+            },
+            Err(err) => guard_failure(req, err),
         }
     }
 }
@@ -204,9 +577,18 @@ pub struct CreateAuthenticatableEntityRB<'r> {
     password: &'r str,
 }
 
+/// Either a session, or an MFA challenge that must be completed via `POST /session/mfa` to obtain one.
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct GetSessionResponse {
-    session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mfa_challenge: Option<String>,
+
+    /// Also set as the `nys-csrf` cookie; repeat it in `X-CSRF-Token` on state-changing cookie-authenticated requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    csrf_token: Option<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -215,73 +597,331 @@ pub struct GetSessionRB<'r> {
     password: &'r str,
 }
 
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct CompleteMfaChallengeRB<'r> {
+    challenge: &'r str,
+    code: &'r str,
+}
+
+/// Creates a session for the AE and hands it to the client as the `nys-session` cookie.
+fn issue_session(cookies: &CookieJar<'_>, config: &AppConfig, context: &RequestContext, redis_client: &redis::Client, entity_id: String) -> Result<GetSessionResponse, ApiError> {
+    // Generate new session token
+    let session_id = Uuid::new_v4();
+
+    // Generate session info
+    let session = AuthenticatedSession {
+        id: session_id.to_string(),
+        entity_id,
+    };
+
+    // Insert session into cache
+    let mut conn = redis_client.get_connection()?;
+    let _ : () = conn.set_ex(format!("session:{}", session.id), &session.entity_id, config.session.ttl_seconds)?;
+
+    audit::record(AuditEvent::new(AuditAction::SessionCreated, AuditOutcome::Success, &session.entity_id, context));
+
+    // Set cookies, the CSRF token must stay readable by scripts so they can echo it
+    let csrf_token = csrf::generate_token();
+
+    cookies.add(session_cookie(config, "nys-session", session.id.clone(), true));
+    cookies.add(session_cookie(config, csrf::CSRF_COOKIE, csrf_token.clone(), false));
+
+    Ok(GetSessionResponse {
+        session_id: Some(session.id),
+        mfa_challenge: None,
+        csrf_token: Some(csrf_token),
+    })
+}
+
+fn session_cookie(config: &AppConfig, name: &'static str, value: String, http_only: bool) -> Cookie<'static> {
+    let mut cookie = Cookie::build(name, value)
+        .secure(config.cookie.secure)
+        .http_only(http_only)
+        .same_site(config.cookie.same_site)
+        .path(config.cookie.path.clone())
+        .max_age(rocket::time::Duration::seconds(config.session.ttl_seconds as i64))
+        .finish();
+
+    if let Some(domain) = &config.cookie.domain {
+        cookie.set_domain(domain.clone());
+    }
+
+    cookie
+}
+
 #[rocket::get("/session", data="<login_info>")]
-pub async fn get_session(cookies: &CookieJar<'_>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, login_info: Json<GetSessionRB<'_>>) -> ApiReturnValue<GetSessionResponse> {
-    let authenticatable_entity = AuthenticatableEntity::retrieve(db_client, redis_client, login_info.id.to_string(), false).await?;
+pub async fn get_session(cookies: &CookieJar<'_>, span: RequestSpan, context: RequestContext, config: &rocket::State<AppConfig>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, login_info: Json<GetSessionRB<'_>>) -> ApiReturnValue<GetSessionResponse> {
+    // Credentials are always verified against the primary store, never the cache
+    let authenticatable_entity = match AuthenticatableEntity::load(db_client, login_info.id.to_string()).instrument((*span).clone()).await {
+        Ok(authenticatable_entity) => authenticatable_entity,
+        Err(ApiError::UserNotFound) => {
+            metrics::record_login(LoginOutcome::Failure);
+            audit::record(AuditEvent::new(AuditAction::Login, AuditOutcome::Failure, login_info.id, &context));
+            return Err(ApiError::UserNotFound);
+        },
+        Err(err) => return Err(err),
+    };
 
     // Check password validity
-    match bcrypt::verify(login_info.password, authenticatable_entity.password_hash.as_str()) {
-        Ok(success) => {
-            if success {
-                // Generate new session token
-                let session_id = Uuid::new_v4();
-
-                // Generate session info
-                let session = AuthenticatedSession {
-                    id: session_id.to_string(),
-                    entity_id: login_info.id.to_string(),
-                };
-
-                // Insert session into cache
-                match redis_client.get_connection() {
-                    Ok(mut conn) => {
-                        match conn.set(format!("session:{}", session.id), session.entity_id) {
-                            Ok(()) => {},
-                            Err(_) => return Err(ApiError::CacheUnavailable),
-                        }
-                    },
-                    Err(_) => return Err(ApiError::CacheUnavailable),
-                }
-
-                // Set cookie
-                let session_cookie = Cookie::build("nys-session", session.id.clone())
-                    //.domain("api.notyoursoftware.com")
-                    .secure(true)
-                    .http_only(true)
-                    .path("/v1")
-                    .finish();
-
-                cookies.add(session_cookie);
-
-                // Send response
-                let response = GetSessionResponse {
-                    session_id: session_id.to_string(),
-                };
-
-                return Ok(ApiResponse(Json(response)));
-            } else {
-                return Err(ApiError::AuthenticationFailed);
-            }
-        },
-        Err(_) => Err(ApiError::AuthenticationFailed),
+    if !authenticatable_entity.verify_password(login_info.password) {
+        tracing::warn!(parent: &*span, entity_id = %authenticatable_entity.id, "failed login");
+        metrics::record_login(LoginOutcome::Failure);
+        audit::record(AuditEvent::new(AuditAction::Login, AuditOutcome::Failure, &authenticatable_entity.id, &context));
+        return Err(ApiError::AuthenticationFailed);
     }
+
+    if !authenticatable_entity.mfa_enabled {
+        tracing::info!(parent: &*span, entity_id = %authenticatable_entity.id, "successful login");
+        metrics::record_login(LoginOutcome::Success);
+        audit::record(AuditEvent::new(AuditAction::Login, AuditOutcome::Success, &authenticatable_entity.id, &context));
+        return Ok(ApiResponse(Json(issue_session(cookies, config, &context, redis_client, authenticatable_entity.id)?)));
+    }
+
+    // Password alone isn't enough, park the login in a short-lived challenge
+    let challenge_id = Uuid::new_v4().to_string();
+
+    let mut conn = redis_client.get_connection()?;
+    let _ : () = conn.set_ex(format!("mfa-challenge:{}", challenge_id), &authenticatable_entity.id, config.session.mfa_challenge_ttl_seconds)?;
+
+    tracing::info!(parent: &*span, entity_id = %authenticatable_entity.id, "issued MFA challenge");
+    metrics::record_login(LoginOutcome::MfaRequired);
+    audit::record(AuditEvent::new(AuditAction::MfaChallengeIssued, AuditOutcome::Success, &authenticatable_entity.id, &context));
+
+    Ok(ApiResponse(Json(GetSessionResponse {
+        session_id: None,
+        mfa_challenge: Some(challenge_id),
+        csrf_token: None,
+    })))
 }
 
-#[rocket::post("/authenticatable_entity", data="<entity_info>")]
-pub async fn create_authenticatable_entity(db_client: &rocket::State<aws_sdk_dynamodb::Client>, entity_info: Json<CreateAuthenticatableEntityRB<'_>>) -> ApiEmptyReturnValue {
-    let new_entity = AuthenticatableEntity::new(entity_info.id.to_string(), entity_info.password.to_string());
+#[rocket::post("/session/mfa", data="<challenge_info>")]
+pub async fn complete_mfa_challenge(cookies: &CookieJar<'_>, span: RequestSpan, context: RequestContext, config: &rocket::State<AppConfig>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, challenge_info: Json<CompleteMfaChallengeRB<'_>>) -> ApiReturnValue<GetSessionResponse> {
+    let challenge_key = format!("mfa-challenge:{}", challenge_info.challenge);
+    let attempts_key = format!("mfa-challenge-attempts:{}", challenge_info.challenge);
+
+    let mut conn = redis_client.get_connection()?;
 
-    let item = serde_dynamo::to_item(new_entity).unwrap();
+    let entity_id: Option<String> = conn.get(&challenge_key)?;
+    let attempts: u32 = conn.incr(&attempts_key, 1)?;
+    let _ : () = conn.expire(&attempts_key, config.session.mfa_challenge_ttl_seconds)?;
 
-    let result = db_client.put_item()
-        .table_name(db::Table::IAM.as_str())
-        .set_item(Some(item))
-        .send().await;
+    let entity_id = match entity_id {
+        Some(entity_id) if attempts <= MFA_MAX_ATTEMPTS => entity_id,
+        _ => return Err(ApiError::InvalidMfaChallenge),
+    };
 
-    match result {
-        Ok(_) => Ok(()),
-        Err(_) => Err(ApiError::MeNoLikeyAWS),
+    let mut authenticatable_entity = AuthenticatableEntity::load(db_client, entity_id).instrument((*span).clone()).await?;
+
+    let mut enrollment = match authenticatable_entity.mfa() {
+        Some(enrollment) if enrollment.is_confirmed() => enrollment.clone(),
+        _ => return Err(ApiError::InvalidMfaChallenge),
+    };
+
+    if !enrollment.verify(challenge_info.code, &context) {
+        tracing::warn!(parent: &*span, entity_id = %authenticatable_entity.id, "failed MFA challenge");
+        metrics::record_login(LoginOutcome::MfaFailure);
+        audit::record(AuditEvent::new(AuditAction::MfaLogin, AuditOutcome::Failure, &authenticatable_entity.id, &context));
+        return Err(ApiError::InvalidMfaCode);
     }
+
+    // Persist the consumed code before handing out the session
+    authenticatable_entity.set_mfa(Some(enrollment));
+    authenticatable_entity.save(db_client, redis_client).instrument((*span).clone()).await?;
+
+    let _ : () = conn.del(&[&challenge_key, &attempts_key])?;
+
+    tracing::info!(parent: &*span, entity_id = %authenticatable_entity.id, "successful MFA login");
+    metrics::record_login(LoginOutcome::MfaSuccess);
+    audit::record(AuditEvent::new(AuditAction::MfaLogin, AuditOutcome::Success, &authenticatable_entity.id, &context));
+
+    Ok(ApiResponse(Json(issue_session(cookies, config, &context, redis_client, authenticatable_entity.id.clone())?)))
 }
 
-pub fn routes() -> Vec<rocket::Route> { rocket::routes![get_session, create_authenticatable_entity] }
\ No newline at end of file
+/// Logs out: the session stops working immediately, wherever its key was copied to.
+#[rocket::delete("/session")]
+pub async fn revoke_session(ae: AuthenticatableEntity, session_key: SessionKey, cookies: &CookieJar<'_>, config: &rocket::State<AppConfig>, redis_client: &rocket::State<redis::Client>) -> ApiEmptyReturnValue {
+    let _ : () = metrics::observe_sync("redis", "DEL", || redis_client.get_connection()?.del(format!("session:{}", session_key.0)))?;
+
+    cookies.remove(session_cookie(config, "nys-session", String::new(), true));
+    cookies.remove(session_cookie(config, csrf::CSRF_COOKIE, String::new(), false));
+
+    audit::record(AuditEvent::new(AuditAction::SessionRevoked, AuditOutcome::Success, &ae.id, ae.context()));
+
+    Ok(())
+}
+
+#[rocket::post("/authenticatable_entity", data="<entity_info>")]
+pub async fn create_authenticatable_entity(span: RequestSpan, context: RequestContext, idempotency_key: IdempotencyKey, config: &rocket::State<AppConfig>, db_client: &rocket::State<aws_sdk_dynamodb::Client>, redis_client: &rocket::State<redis::Client>, entity_info: Json<CreateAuthenticatableEntityRB<'_>>) -> ApiEmptyReturnValue {
+    let reservation = match idempotency_key.claim::<()>(redis_client, &config.idempotency, "create_authenticatable_entity", &*entity_info)? {
+        Claim::Replay(()) => return Ok(()),
+        Claim::Proceed(reservation) => reservation,
+    };
+
+    let created = insert_authenticatable_entity(db_client, &entity_info).await;
+    reservation.settle(redis_client, &created);
+    let new_entity = created?;
+
+    tracing::info!(parent: &*span, entity_id = %new_entity.id, "created AE");
+
+    // Registration is anonymous, so the new AE is its own actor
+    audit::record(AuditEvent::new(AuditAction::EntityCreated, AuditOutcome::Success, &new_entity.id, &context).target(new_entity.id.clone()));
+
+    Ok(())
+}
+
+async fn insert_authenticatable_entity(db_client: &aws_sdk_dynamodb::Client, entity_info: &CreateAuthenticatableEntityRB<'_>) -> Result<AuthenticatableEntity, ApiError> {
+    let new_entity = AuthenticatableEntity::new(entity_info.id.to_string(), entity_info.password.to_string())?;
+
+    let item = serde_dynamo::to_item(&new_entity)?;
+
+    // Without the condition, registering an existing id would take over that AE
+    let put = db_client.put_item()
+        .table_name(db::Table::Iam.as_str())
+        .set_item(Some(item));
+
+    versioning::expect_absent(put, "id").send().await
+        .map_err(|err| versioning::conflict_or(err, ApiError::AlreadyExists))?;
+
+    Ok(new_entity)
+}
+
+guarded_routes![get_session, complete_mfa_challenge, revoke_session, create_authenticatable_entity];
+
+#[cfg(test)]
+mod iam_tests {
+    use super::*;
+
+    fn definition(permissions: &[&str]) -> PermissionsDefinition {
+        PermissionsDefinition {
+            permissions: permissions.iter().map(|p| PermissionGrant::Unconditional(p.to_string())).collect(),
+        }
+    }
+
+    fn context(source_ip: &str, received_at: u64) -> RequestContext {
+        RequestContext {
+            source_ip: Some(source_ip.parse().unwrap()),
+            received_at,
+            request_id: None,
+        }
+    }
+
+    fn granted(def: &PermissionsDefinition, privilege: &str, context: &RequestContext) -> Option<String> {
+        def.matching_grant(privilege, context).unwrap().map(|grant| grant.permission().to_string())
+    }
+
+    #[test]
+    fn wildcard_grant_matches() {
+        let def = definition(&["nys:*:nate:*:*"]);
+        let ctx = RequestContext::default();
+
+        assert_eq!(granted(&def, "nys:tasker:nate:TaskList:Read", &ctx), Some("nys:*:nate:*:*".to_string()));
+        assert_eq!(granted(&def, "nys:tasker:bob:TaskList:Read", &ctx), None);
+    }
+
+    #[test]
+    fn prefix_wildcard_grant_matches() {
+        let def = definition(&["nys:tasker:nate:Task*:Re*"]);
+        let ctx = RequestContext::default();
+
+        assert!(granted(&def, "nys:tasker:nate:TaskList:Read", &ctx).is_some());
+        assert!(granted(&def, "nys:tasker:nate:TaskList:Write", &ctx).is_none());
+    }
+
+    #[test]
+    fn wildcard_matches_everything_before_it_as_a_prefix() {
+        assert!(privilege_component_matches("*", "TaskList"));
+        assert!(privilege_component_matches("*", ""));
+        assert!(privilege_component_matches("Task*", "Task"));
+        assert!(privilege_component_matches("Task*", "TaskList"));
+        assert!(privilege_component_matches("Task*List", "TaskLog"));
+        assert!(!privilege_component_matches("Task*", "Tas"));
+        assert!(!privilege_component_matches("Task*", "MyTaskList"));
+        assert!(!privilege_component_matches("TaskList", "TaskLists"));
+        assert!(privilege_component_matches("TaskList", "TaskList"));
+    }
+
+    #[test]
+    fn later_grants_are_considered() {
+        let def = definition(&["nys:iam:nate:*:*", "malformed", "nys:tasker:nate:TaskList:Read"]);
+        let ctx = RequestContext::default();
+
+        assert_eq!(granted(&def, "nys:tasker:nate:TaskList:Read", &ctx), Some("nys:tasker:nate:TaskList:Read".to_string()));
+    }
+
+    #[test]
+    fn malformed_request_is_rejected() {
+        let def = definition(&["nys:*:nate:*:*"]);
+
+        assert!(matches!(def.matching_grant("nys:tasker:nate", &RequestContext::default()), Err(ApiError::MalformedPermission)));
+    }
+
+    #[test]
+    fn conditional_grant_respects_time_window() {
+        let def: PermissionsDefinition = serde_json::from_str(r#"{
+            "permissions": [{ "permission": "nys:*:nate:*:*", "conditions": { "valid_from": 100, "valid_until": 200 } }]
+        }"#).unwrap();
+
+        assert!(granted(&def, "nys:tasker:nate:TaskList:Read", &context("127.0.0.1", 50)).is_none());
+        assert!(granted(&def, "nys:tasker:nate:TaskList:Read", &context("127.0.0.1", 150)).is_some());
+        assert!(granted(&def, "nys:tasker:nate:TaskList:Read", &context("127.0.0.1", 250)).is_none());
+    }
+
+    #[test]
+    fn conditional_grant_respects_source_ranges() {
+        let def: PermissionsDefinition = serde_json::from_str(r#"{
+            "permissions": [
+                "nys:tasker:nate:TaskList:Read",
+                { "permission": "nys:*:*:*:*", "conditions": { "source_ranges": ["10.1.0.0/16", "2001:db8::/32"] } }
+            ]
+        }"#).unwrap();
+
+        assert!(granted(&def, "nys:iam:bob:AuthenticatableEntity:Read", &context("10.1.42.7", 0)).is_some());
+        assert!(granted(&def, "nys:iam:bob:AuthenticatableEntity:Read", &context("::ffff:10.1.42.7", 0)).is_some());
+        assert!(granted(&def, "nys:iam:bob:AuthenticatableEntity:Read", &context("2001:db8::1", 0)).is_some());
+        assert!(granted(&def, "nys:iam:bob:AuthenticatableEntity:Read", &context("10.2.0.1", 0)).is_none());
+        assert!(granted(&def, "nys:iam:bob:AuthenticatableEntity:Read", &RequestContext { source_ip: None, received_at: 0, request_id: None }).is_none());
+    }
+
+    #[test]
+    fn forwarded_address_is_only_believed_from_trusted_proxies() {
+        let proxies = vec!["10.0.0.0/24".to_string()];
+        let forwarded = Some("203.0.113.7".parse().unwrap());
+
+        assert_eq!(resolve_client_address(Some("10.0.0.5".parse().unwrap()), forwarded, &proxies), forwarded);
+        assert_eq!(resolve_client_address(Some("10.0.0.5".parse().unwrap()), None, &proxies), Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(resolve_client_address(Some("198.51.100.1".parse().unwrap()), forwarded, &proxies), Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(resolve_client_address(Some("198.51.100.1".parse().unwrap()), forwarded, &[]), Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ip_ranges_parse_edge_cases() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+
+        assert!(ip_in_range(ip, "0.0.0.0/0"));
+        assert!(ip_in_range(ip, "192.168.1.20"));
+        assert!(!ip_in_range(ip, "192.168.1.21/32"));
+        assert!(!ip_in_range(ip, "192.168.1.0/33"));
+        assert!(!ip_in_range(ip, "not-an-ip/8"));
+    }
+
+    #[test]
+    fn entity_ids_cannot_widen_their_own_grant() {
+        for id in ["*", "a*", "nate:TaskList", "nate#activity", ""] {
+            assert!(matches!(AuthenticatableEntity::new(id.to_string(), "hunter2".to_string()), Err(ApiError::InvalidEntityId)), "{:?} was accepted", id);
+        }
+
+        assert!(AuthenticatableEntity::new("nate.k-1".to_string(), "hunter2".to_string()).is_ok());
+    }
+
+    #[test]
+    fn credentials_are_not_exposed() {
+        let mut ae = AuthenticatableEntity::new("nate".to_string(), "hunter2".to_string()).unwrap();
+        assert!(ae.verify_password("hunter2"));
+        assert!(!serde_json::to_string(&ae.view()).unwrap().contains("password_hash"));
+
+        // What `retrieve` caches
+        ae.password_hash = None;
+        assert!(!serde_json::to_string(&ae).unwrap().contains("password_hash"));
+        assert!(!ae.verify_password("hunter2"));
+    }
+}
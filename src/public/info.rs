@@ -0,0 +1,32 @@
+use rocket::serde::json::Json;
+use crate::api_response::{ApiResponse, ApiReturnValue, ErrorKind};
+use crate::authorization::guarded_routes;
+
+#[derive(serde::Serialize)]
+pub struct ErrorCatalogEntry {
+    code: u8,
+    name: &'static str,
+    http_status: u16,
+    description: &'static str,
+}
+
+#[derive(serde::Serialize)]
+pub struct ErrorCatalog {
+    errors: Vec<ErrorCatalogEntry>,
+}
+
+#[rocket::get("/errors")]
+pub fn get_error_catalog() -> ApiReturnValue<ErrorCatalog> {
+    let errors = ErrorKind::ALL.iter()
+        .map(|kind| ErrorCatalogEntry {
+            code: kind.code(),
+            name: kind.name(),
+            http_status: kind.http_status().code,
+            description: kind.description(),
+        })
+        .collect();
+
+    Ok(ApiResponse(Json(ErrorCatalog { errors })))
+}
+
+guarded_routes![get_error_catalog];
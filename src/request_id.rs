@@ -0,0 +1,86 @@
+use std::fmt;
+use rocket::{Data, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Incoming IDs longer than this, or with characters outside `[A-Za-z0-9._-]`, are replaced rather than echoed.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Identifies a single request across responses and logs. Also usable as a request guard.
+#[derive(Clone, Debug)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// The ID assigned to `req`, generating one if the fairing hasn't run.
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r RequestId {
+        req.local_cache(|| RequestId(Uuid::new_v4().to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn is_acceptable(candidate: &str) -> bool {
+        !candidate.is_empty()
+            && candidate.len() <= MAX_REQUEST_ID_LENGTH
+            && candidate.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(req).clone())
+    }
+}
+
+/// Assigns every request an ID, honouring a well-formed incoming `X-Request-Id`, and echoes it back.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let request_id = match req.headers().get_one(REQUEST_ID_HEADER) {
+            Some(incoming) if RequestId::is_acceptable(incoming) => incoming.to_string(),
+            _ => Uuid::new_v4().to_string(),
+        };
+
+        req.local_cache(|| RequestId(request_id));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(REQUEST_ID_HEADER, RequestId::of(req).as_str().to_string()));
+    }
+}
+
+#[cfg(test)]
+mod request_id_tests {
+    use super::*;
+
+    #[test]
+    fn only_well_formed_ids_are_honoured() {
+        assert!(RequestId::is_acceptable("3f2c1a9e-support.ticket_42"));
+        assert!(!RequestId::is_acceptable(""));
+        assert!(!RequestId::is_acceptable("evil\r\nSet-Cookie: x"));
+        assert!(!RequestId::is_acceptable(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+    }
+}
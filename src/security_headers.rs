@@ -0,0 +1,110 @@
+use rocket::{Request, Response};
+use rocket::http::Header;
+use rocket::fairing::{Fairing, Info, Kind};
+use crate::config::SecurityHeadersConfig;
+use crate::mounts::PerMount;
+
+/// The headers added to responses under one mount. Headers already set by a handler are left alone.
+#[derive(Clone)]
+pub struct HeaderPolicy {
+    headers: Vec<(&'static str, String)>,
+    /// Only sent with JSON bodies, where it stops a mis-sniffed response from running anything.
+    content_security_policy: String,
+}
+
+impl HeaderPolicy {
+    pub fn new(config: &SecurityHeadersConfig) -> HeaderPolicy {
+        let mut headers = vec![
+            ("X-Content-Type-Options", "nosniff".to_string()),
+            ("X-Frame-Options", config.frame_options.clone()),
+            ("Referrer-Policy", config.referrer_policy.clone()),
+        ];
+
+        // A zero max-age would tell browsers to forget HSTS, leave the header out instead
+        if config.hsts_max_age_seconds > 0 {
+            headers.push(("Strict-Transport-Security", format!["max-age={}; includeSubDomains", config.hsts_max_age_seconds]));
+        }
+
+        HeaderPolicy {
+            headers,
+            content_security_policy: config.content_security_policy.clone(),
+        }
+    }
+
+    /// Adds a header, replacing any value this policy already had for it.
+    pub fn with(mut self, name: &'static str, value: &str) -> HeaderPolicy {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+pub struct SecurityHeaders {
+    policies: PerMount<HeaderPolicy>,
+}
+
+impl SecurityHeaders {
+    /// `fallback` applies to every path not covered by a more specific `mount`.
+    pub fn new(fallback: HeaderPolicy) -> SecurityHeaders {
+        SecurityHeaders { policies: PerMount::new(fallback) }
+    }
+
+    pub fn mount(self, base: &str, policy: HeaderPolicy) -> SecurityHeaders {
+        SecurityHeaders { policies: self.policies.mount(base, policy) }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let policy = self.policies.get(request.uri().path().as_str());
+
+        for (name, value) in &policy.headers {
+            if !response.headers().contains(*name) {
+                response.set_header(Header::new(*name, value.clone()));
+            }
+        }
+
+        let is_json = response.content_type().is_some_and(|content_type| content_type.is_json());
+
+        if is_json && !response.headers().contains("Content-Security-Policy") {
+            response.set_header(Header::new("Content-Security-Policy", policy.content_security_policy.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod security_headers_tests {
+    use super::*;
+
+    fn config(hsts_max_age_seconds: u64) -> SecurityHeadersConfig {
+        SecurityHeadersConfig {
+            hsts_max_age_seconds,
+            frame_options: "DENY".to_string(),
+            referrer_policy: "no-referrer".to_string(),
+            content_security_policy: "default-src 'none'".to_string(),
+        }
+    }
+
+    fn value<'a>(policy: &'a HeaderPolicy, name: &str) -> Option<&'a str> {
+        policy.headers.iter().find(|(existing, _)| *existing == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn overrides_replace_headers() {
+        let policy = HeaderPolicy::new(&config(0)).with("X-Frame-Options", "SAMEORIGIN").with("Cache-Control", "no-store");
+
+        assert_eq!(value(&policy, "Strict-Transport-Security"), None);
+        assert_eq!(value(&policy, "X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(value(&policy, "Cache-Control"), Some("no-store"));
+        assert_eq!(policy.headers.iter().filter(|(name, _)| *name == "X-Frame-Options").count(), 1);
+        assert!(value(&HeaderPolicy::new(&config(60)), "Strict-Transport-Security").is_some());
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::metrics;
+use crate::private::tasker::Task;
+
+/*
+Live task changes. The tasker write paths publish every change to the list's Redis channel, and each
+instance runs a single listener that forwards everything published on any list into an in-process
+broadcast channel, from where the `task/events` streams pick the lists their clients asked for.
+
+Delivery is best effort. Subscribers that fall behind, or miss changes while Redis is unreachable,
+get a `Resync` telling them to fetch the list again.
+ */
+
+const CHANNEL_PREFIX: &str = "tasks:";
+
+/// Changes buffered per subscriber before it counts as lagging.
+const BROADCAST_CAPACITY: usize = 1024;
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskChange {
+    Created,
+    Updated,
+    /// An update that marked the task completed. Streams send it as `Updated`, see `TaskEvent::streamed`.
+    Completed,
+    Deleted,
+}
+
+impl TaskChange {
+    /// The SSE event and webhook `X-NYS-Event` name.
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskChange::Created => "created",
+            TaskChange::Updated => "updated",
+            TaskChange::Completed => "completed",
+            TaskChange::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct TaskEvent {
+    pub change: TaskChange,
+    pub owner: String,
+    pub id: String,
+
+    /// The task after the change, absent for deletions and where the writer didn't read it back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<Task>,
+}
+
+impl TaskEvent {
+    pub fn new(change: TaskChange, owner: &str, id: &str, task: Option<Task>) -> TaskEvent {
+        TaskEvent { change, owner: owner.to_string(), id: id.to_string(), task }
+    }
+
+    /// The event as streams send it. Streams only ever had `created`, `updated` and `deleted`.
+    pub fn streamed(mut self) -> TaskEvent {
+        if self.change == TaskChange::Completed {
+            self.change = TaskChange::Updated;
+        }
+
+        self
+    }
+}
+
+#[derive(Clone)]
+pub enum Notification {
+    Change(TaskEvent),
+    /// Changes may have been missed.
+    Resync,
+}
+
+/// Managed state handing out subscriptions to the changes seen by this instance's listener.
+pub struct TaskEvents(broadcast::Sender<Notification>);
+
+impl TaskEvents {
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.0.subscribe()
+    }
+}
+
+fn channel_of(owner: &str) -> String {
+    format!["{}{}", CHANNEL_PREFIX, owner]
+}
+
+/// Publishes changes to their lists' channels. The writes already happened, so failures are only logged.
+pub fn publish(redis_client: &redis::Client, events: &[TaskEvent]) {
+    if events.is_empty() {
+        return;
+    }
+
+    if let Err(err) = try_publish(redis_client, events) {
+        tracing::error!(error = %err, count = events.len(), "failed to publish task events");
+    }
+}
+
+fn try_publish(redis_client: &redis::Client, events: &[TaskEvent]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut pipeline = redis::pipe();
+
+    for event in events {
+        pipeline.cmd("PUBLISH").arg(channel_of(&event.owner)).arg(serde_json::to_string(event)?).ignore();
+    }
+
+    let mut conn = redis_client.get_connection()?;
+    let _ : () = metrics::observe_sync("redis", "PUBLISH", || pipeline.query(&mut conn))?;
+
+    Ok(())
+}
+
+/// Starts this instance's listener thread, reconnecting with backoff whenever Redis goes away.
+pub fn spawn_listener(redis_client: redis::Client) -> TaskEvents {
+    let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+    let forward = sender.clone();
+
+    std::thread::Builder::new()
+        .name("task-events".to_string())
+        .spawn(move || {
+            let mut delay = Duration::from_secs(1);
+
+            loop {
+                if let Err(err) = listen(&redis_client, &forward, &mut delay) {
+                    tracing::error!(error = %err, retry_in = ?delay, "task event listener disconnected");
+                }
+
+                // Whatever was published meanwhile is lost
+                let _ = forward.send(Notification::Resync);
+
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        })
+        .expect("failed to start the task event listener");
+
+    TaskEvents(sender)
+}
+
+fn listen(redis_client: &redis::Client, forward: &broadcast::Sender<Notification>, delay: &mut Duration) -> redis::RedisResult<()> {
+    let mut conn = redis_client.get_connection()?;
+    let mut pubsub = conn.as_pubsub();
+    pubsub.psubscribe(format!["{}*", CHANNEL_PREFIX])?;
+
+    *delay = Duration::from_secs(1);
+    tracing::info!("listening for task events");
+
+    loop {
+        let payload: String = pubsub.get_message()?.get_payload()?;
+
+        match serde_json::from_str::<TaskEvent>(&payload) {
+            // Nobody listening is fine
+            Ok(event) => { let _ = forward.send(Notification::Change(event)); },
+            Err(err) => tracing::warn!(error = %err, "ignored malformed task event"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod task_events_tests {
+    use super::*;
+
+    #[test]
+    fn events_round_trip_through_json() {
+        let event = TaskEvent::new(TaskChange::Deleted, "nate", "a", None);
+        let payload = serde_json::to_string(&event).unwrap();
+
+        assert_eq!(payload, r#"{"change":"deleted","owner":"nate","id":"a"}"#);
+        assert_eq!(serde_json::from_str::<TaskEvent>(&payload).unwrap().change, TaskChange::Deleted);
+        assert_eq!(channel_of("nate"), "tasks:nate");
+    }
+
+    #[test]
+    fn streams_report_completions_as_updates() {
+        let event = TaskEvent::new(TaskChange::Completed, "nate", "a", None).streamed();
+
+        assert_eq!(event.change.name(), "updated");
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"change":"updated","owner":"nate","id":"a"}"#);
+        assert_eq!(TaskEvent::new(TaskChange::Deleted, "nate", "a", None).streamed().change, TaskChange::Deleted);
+    }
+}
@@ -0,0 +1,91 @@
+use std::ops::Deref;
+use std::time::Instant;
+use rocket::{Data, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{FromRequest, Outcome};
+use tracing::Span;
+use tracing::field::Empty;
+use tracing_subscriber::EnvFilter;
+use crate::request_id::RequestId;
+
+/// Installs the global subscriber. Verbosity follows `RUST_LOG`, defaulting to `info`.
+pub fn init(json: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    if json {
+        builder.json().try_init()
+    } else {
+        builder.try_init()
+    }
+}
+
+/// The span covering a whole request. Handlers use it as the parent of their own events and I/O spans.
+#[derive(Clone)]
+pub struct RequestSpan(Span);
+
+impl RequestSpan {
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r RequestSpan {
+        req.local_cache(|| RequestSpan(tracing::info_span!(
+            "request",
+            request_id = %RequestId::of(req),
+            method = %req.method(),
+            uri = %req.uri(),
+            route = Empty,
+            entity_id = Empty,
+            status = Empty,
+            latency_ms = Empty,
+        )))
+    }
+}
+
+impl Deref for RequestSpan {
+    type Target = Span;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestSpan {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestSpan::of(req).clone())
+    }
+}
+
+struct RequestStart(Instant);
+
+/// Opens a span per request and closes it with the matched route, status and latency.
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+        RequestSpan::of(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        let span = RequestSpan::of(req);
+        let latency = req.local_cache(|| RequestStart(Instant::now())).0.elapsed();
+
+        if let Some(route) = req.route() {
+            span.record("route", tracing::field::display(&route.uri));
+        }
+
+        span.record("status", response.status().code);
+        span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+
+        tracing::info!(parent: &**span, "request completed");
+    }
+}
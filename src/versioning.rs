@@ -0,0 +1,105 @@
+use aws_sdk_dynamodb::client::fluent_builders::PutItem;
+use aws_sdk_dynamodb::error::PutItemError;
+use aws_sdk_dynamodb::model::AttributeValue;
+use aws_sdk_dynamodb::types::SdkError;
+use rocket::Request;
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome};
+use crate::api_response::{guard_failure, ApiError};
+
+/*
+Optimistic concurrency. Every stored item carries a `version` that each write bumps by one, and writes
+only succeed if the stored version is still the one the change was based on. Clients see the version as
+a strong `ETag` and can make their updates conditional with `If-Match`.
+
+Versions start at 1 on creation; items written before versioning existed read as version 0.
+ */
+
+pub fn etag(version: u64) -> Header<'static> {
+    Header::new("ETag", format!["\"{}\"", version])
+}
+
+/// Makes a put succeed only if the stored item is still at `expected`, i.e. nobody wrote it since it was read.
+pub fn expect_version(put: PutItem, expected: u64) -> PutItem {
+    let put = put.expression_attribute_names("#version", "version");
+
+    if expected == 0 {
+        put.condition_expression("attribute_not_exists(#version)")
+    } else {
+        put.condition_expression("#version = :expected_version")
+            .expression_attribute_values(":expected_version", AttributeValue::N(expected.to_string()))
+    }
+}
+
+/// Makes a put succeed only if no item with the same key exists yet.
+pub fn expect_absent(put: PutItem, key_attribute: &str) -> PutItem {
+    put.expression_attribute_names("#key", key_attribute)
+        .condition_expression("attribute_not_exists(#key)")
+}
+
+/// Turns a failed condition into `on_conflict`, any other failure into the usual AWS error.
+pub fn conflict_or(err: SdkError<PutItemError>, on_conflict: ApiError) -> ApiError {
+    match &err {
+        SdkError::ServiceError { err, .. } if err.is_conditional_check_failed_exception() => on_conflict,
+        _ => err.into(),
+    }
+}
+
+/// The `If-Match` precondition of an update. Absent or `*` accepts any current version.
+pub struct IfMatch(Option<u64>);
+
+impl IfMatch {
+    pub fn check(&self, current: u64) -> Result<(), ApiError> {
+        match self.0 {
+            Some(expected) if expected != current => Err(ApiError::PreconditionFailed),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse_if_match(value: &str) -> Option<IfMatch> {
+    let value = value.trim();
+
+    if value == "*" {
+        return Some(IfMatch(None));
+    }
+
+    // Weak tags compare the same here, there is only one representation per version
+    let tag = value.strip_prefix("W/").unwrap_or(value);
+
+    tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok().map(|version| IfMatch(Some(version)))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one("If-Match") {
+            None => Outcome::Success(IfMatch(None)),
+            Some(value) => match parse_if_match(value) {
+                Some(if_match) => Outcome::Success(if_match),
+                // A tag we never issued cannot match the current version
+                None => guard_failure(req, ApiError::PreconditionFailed),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod versioning_tests {
+    use super::*;
+
+    #[test]
+    fn if_match_accepts_issued_etags() {
+        assert_eq!(parse_if_match("\"3\"").and_then(|if_match| if_match.0), Some(3));
+        assert_eq!(parse_if_match("W/\"3\"").and_then(|if_match| if_match.0), Some(3));
+        assert!(parse_if_match("*").is_some_and(|if_match| if_match.0.is_none()));
+        assert!(parse_if_match("3").is_none());
+        assert!(parse_if_match("\"abc\"").is_none());
+
+        assert!(IfMatch(Some(3)).check(3).is_ok());
+        assert!(matches!(IfMatch(Some(3)).check(4), Err(ApiError::PreconditionFailed)));
+        assert!(IfMatch(None).check(4).is_ok());
+    }
+}
@@ -0,0 +1,716 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use aws_sdk_dynamodb::model::AttributeValue;
+use hmac::Mac;
+use hyper::Uri;
+use hyper::client::HttpConnector;
+use hyper::client::connect::Connect;
+use hyper::client::connect::dns::Name;
+use hyper::service::Service;
+use hyper_rustls::HttpsConnector;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::api_response::{ApiError, ErrorCause};
+use crate::config::WebhooksConfig;
+use crate::db;
+use crate::metrics;
+use crate::public::iam::ip_in_range;
+use crate::task_events::{TaskChange, TaskEvent};
+
+/*
+Outgoing webhooks. Entities subscribe a URL to some of a task list's changes, and the tasker write paths
+`dispatch` every change here. A background task looks up the matching subscriptions and POSTs each one
+the change as JSON, retrying failed attempts with exponential backoff. Every delivery and its attempts
+are logged in the deliveries table, and any delivery can be sent again as a new one.
+
+Receivers verify `X-NYS-Signature: t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">` with the
+subscription secret, and should reject stale timestamps. `event_id` in the body stays the same across
+redeliveries so receivers can deduplicate.
+
+Retries are scheduled in memory, a restart abandons them with the delivery left `pending`.
+
+Receivers must be on the public internet. Their host is resolved again for every attempt, addresses in
+`NON_PUBLIC_RANGES` are dropped, and the connection goes to one of the addresses that were let through,
+so neither a URL nor a later DNS change can point deliveries at this host or an internal network.
+ */
+
+pub const SIGNATURE_HEADER: &str = "X-NYS-Signature";
+pub const EVENT_HEADER: &str = "X-NYS-Event";
+pub const DELIVERY_HEADER: &str = "X-NYS-Delivery";
+
+/// Jobs waiting for the dispatcher. When it falls this far behind, changes are dropped and logged.
+const QUEUE_CAPACITY: usize = 1024;
+
+const USER_AGENT: &str = "not-your-api-webhooks";
+
+/// Attempt errors are kept short in the log.
+const MAX_ERROR_LENGTH: usize = 200;
+
+/// Where receivers may not be: unspecified, this host, private and shared networks, link-local (which has
+/// the cloud metadata services), documentation, benchmarking, multicast and reserved ranges. IPv4-mapped
+/// IPv6 addresses are checked as the IPv4 address they map to.
+const NON_PUBLIC_RANGES: &[&str] = &[
+    "0.0.0.0/8", "10.0.0.0/8", "100.64.0.0/10", "127.0.0.0/8", "169.254.0.0/16", "172.16.0.0/12", "192.0.0.0/24",
+    "192.0.2.0/24", "192.168.0.0/16", "198.18.0.0/15", "198.51.100.0/24", "203.0.113.0/24", "224.0.0.0/4", "240.0.0.0/4",
+    "::/128", "::1/128", "64:ff9b::/96", "100::/64", "2001:db8::/32", "fc00::/7", "fe80::/10", "ff00::/8",
+];
+
+static SINK: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+type HttpClient = hyper::Client<HttpsConnector<PublicConnector>>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Subscription {
+    pub owner: String,
+    pub id: String,
+    pub url: String,
+    /// Signing key, never sent back to clients.
+    pub secret: String,
+    pub events: Vec<TaskChange>,
+    pub created_at: u64,
+}
+
+/// What clients see of a subscription.
+#[derive(serde::Serialize)]
+pub struct SubscriptionView {
+    owner: String,
+    id: String,
+    url: String,
+    events: Vec<TaskChange>,
+    created_at: u64,
+}
+
+impl From<Subscription> for SubscriptionView {
+    fn from(subscription: Subscription) -> Self {
+        SubscriptionView {
+            owner: subscription.owner,
+            id: subscription.id,
+            url: subscription.url,
+            events: subscription.events,
+            created_at: subscription.created_at,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Attempt {
+    /// Unix millis.
+    at: u64,
+    duration_ms: u64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+
+    /// Why no response arrived, e.g. a timeout or refused connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Attempt {
+    fn succeeded(&self) -> bool {
+        self.status_code.is_some_and(|status| (200..300).contains(&status))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Delivery {
+    pub subscription: String,
+    /// `<unix millis, zero padded>-<uuid>`, so a subscription's deliveries sort by time.
+    pub id: String,
+    pub event: TaskChange,
+    /// The exact body that is signed and sent.
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<Attempt>,
+
+    /// The delivery this one sends again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redelivery_of: Option<String>,
+}
+
+impl Delivery {
+    fn new(subscription: &str, event: TaskChange, payload: String, redelivery_of: Option<String>) -> Delivery {
+        Delivery {
+            subscription: subscription.to_string(),
+            id: format!["{:013}-{}", db::now_millis(), Uuid::new_v4()],
+            event,
+            payload,
+            status: DeliveryStatus::Pending,
+            attempts: Vec::new(),
+            redelivery_of,
+        }
+    }
+
+    /// A new delivery of the same payload.
+    pub fn redelivery(&self) -> Delivery {
+        Delivery::new(&self.subscription, self.event, self.payload.clone(), Some(self.id.clone()))
+    }
+
+    fn record(&mut self, attempt: Attempt, max_attempts: u32) {
+        self.status = if attempt.succeeded() {
+            DeliveryStatus::Succeeded
+        } else if self.attempts.len() + 1 >= max_attempts as usize {
+            DeliveryStatus::Failed
+        } else {
+            DeliveryStatus::Pending
+        };
+
+        self.attempts.push(attempt);
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    event_id: String,
+    event: TaskChange,
+    /// Unix seconds.
+    occurred_at: u64,
+    data: &'a TaskEvent,
+}
+
+fn payload_of(event: &TaskEvent) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&Payload {
+        event_id: Uuid::new_v4().to_string(),
+        event: event.change,
+        occurred_at: db::now_millis() / 1000,
+        data: event,
+    })
+}
+
+#[derive(serde::Serialize)]
+pub struct DeliveryPage {
+    deliveries: Vec<Delivery>,
+    /// Pass back as `cursor` for the next page; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+enum Job {
+    Change(TaskEvent),
+    Deliver(Subscription, Delivery),
+}
+
+/// A job sure to fit the dispatcher's queue, see `reserve`.
+pub struct Scheduled(mpsc::OwnedPermit<Job>);
+
+impl Scheduled {
+    pub fn deliver(self, subscription: Subscription, delivery: Delivery) {
+        self.0.send(Job::Deliver(subscription, delivery));
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    timeout: Duration,
+}
+
+impl RetryPolicy {
+    fn new(config: &WebhooksConfig) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: config.max_attempts,
+            initial_backoff: Duration::from_secs(config.initial_backoff_seconds),
+            max_backoff: Duration::from_secs(config.max_backoff_seconds),
+            timeout: Duration::from_secs(config.timeout_seconds),
+        }
+    }
+
+    /// How long to wait after the given number of failed attempts.
+    fn backoff(&self, failed_attempts: usize) -> Duration {
+        let doublings = failed_attempts.saturating_sub(1).min(31) as u32;
+
+        self.initial_backoff.saturating_mul(1 << doublings).min(self.max_backoff)
+    }
+}
+
+/// Whether `url` may receive webhooks: absolute, with a host, over https unless plain http is allowed.
+pub fn is_valid_url(url: &str, allow_http: bool) -> bool {
+    let uri: hyper::Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+
+    let scheme_allowed = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => allow_http,
+        _ => false,
+    };
+
+    let host_allowed = match uri.host() {
+        Some(host) => !host.is_empty() && ip_literal(host).is_none_or(is_public_address),
+        None => false,
+    };
+
+    scheme_allowed && host_allowed
+}
+
+fn is_public_address(ip: IpAddr) -> bool {
+    !NON_PUBLIC_RANGES.iter().any(|range| ip_in_range(ip, range))
+}
+
+/// The address a URI host spells out, with IPv6 brackets.
+fn ip_literal(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Resolves receiver hosts to their public addresses only. `HttpConnector` connects to what this returns.
+#[derive(Clone)]
+struct PublicResolver;
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|address| is_public_address(address.ip()))
+                .collect();
+
+            if addresses.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!["{} has no public address", name]));
+            }
+
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+/// Connects to receivers on the public internet only. Also refuses non-public IP literals, which
+/// `HttpConnector` connects to without asking the resolver.
+#[derive(Clone)]
+pub struct PublicConnector(HttpConnector<PublicResolver>);
+
+impl PublicConnector {
+    fn new() -> PublicConnector {
+        let mut http = HttpConnector::new_with_resolver(PublicResolver);
+        http.enforce_http(false);
+
+        PublicConnector(http)
+    }
+}
+
+impl Service<Uri> for PublicConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.0.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        if let Some(ip) = uri.host().and_then(ip_literal).filter(|ip| !is_public_address(*ip)) {
+            return Box::pin(async move { Err(format!["{} is not a public address", ip].into()) });
+        }
+
+        let connecting = self.0.call(uri);
+        Box::pin(async move { connecting.await.map_err(Into::into) })
+    }
+}
+
+/// The `X-NYS-Signature` value for `body` sent at unix second `timestamp`.
+pub fn signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!["{}.{}", timestamp, body].as_bytes());
+
+    let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!["{:02x}", byte]).collect();
+
+    format!["t={},v1={}", timestamp, digest]
+}
+
+/// Queues task changes for delivery. Never blocks and never fails the caller.
+pub fn dispatch(events: &[TaskEvent]) {
+    let sink = match SINK.get() {
+        Some(sink) => sink,
+        None => return,
+    };
+
+    for event in events {
+        if let Err(err) = sink.try_send(Job::Change(event.clone())) {
+            tracing::error!(error = %err, owner = %event.owner, task_id = %event.id, "dropped webhook event");
+        }
+    }
+}
+
+/// Reserves room for a delivery before it is written, so nothing gets logged that won't be attempted.
+pub fn reserve() -> Result<Scheduled, ApiError> {
+    let sink = SINK.get().ok_or_else(|| ApiError::Internal(ErrorCause::new("webhook dispatcher is not running")))?;
+
+    sink.clone().try_reserve_owned()
+        .map(Scheduled)
+        .map_err(|_| ApiError::Internal(ErrorCause::new("webhook queue is full")))
+}
+
+/// TLS settings for receivers, trusting the system's root certificates.
+fn tls_config() -> Result<rustls::ClientConfig, String> {
+    let mut tls = rustls::ClientConfig::new();
+
+    tls.root_store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), err)) => {
+            tracing::warn!(error = %err, "could not load all system root certificates");
+            store
+        },
+        Err((None, err)) => return Err(format!["cannot read the system root certificates: {}", err]),
+    };
+
+    if tls.root_store.is_empty() {
+        return Err("found no system root certificates".to_string());
+    }
+
+    // The client only speaks HTTP/1.1
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(tls)
+}
+
+/// Starts the task that turns dispatched changes into deliveries. Without it, changes are not delivered,
+/// which is also what happens when there are no root certificates to verify receivers with.
+pub fn spawn_dispatcher(db_client: aws_sdk_dynamodb::Client, config: &WebhooksConfig) {
+    let tls = match tls_config() {
+        Ok(tls) => tls,
+        Err(err) => {
+            tracing::error!(error = %err, "webhook delivery is disabled");
+            return;
+        },
+    };
+
+    let (sender, mut receiver) = mpsc::channel::<Job>(QUEUE_CAPACITY);
+
+    if SINK.set(sender).is_err() {
+        return;
+    }
+
+    let policy = RetryPolicy::new(config);
+    let http: HttpClient = hyper::Client::builder().build(HttpsConnector::from((PublicConnector::new(), tls)));
+
+    tokio::spawn(async move {
+        while let Some(job) = receiver.recv().await {
+            match job {
+                Job::Change(event) => {
+                    if let Err(err) = fan_out(&db_client, &http, policy, &event).await {
+                        tracing::error!(error = %err, owner = %event.owner, task_id = %event.id, "failed to schedule webhook deliveries");
+                    }
+                },
+                Job::Deliver(subscription, delivery) => {
+                    tokio::spawn(deliver(db_client.clone(), http.clone(), policy, subscription, delivery));
+                },
+            }
+        }
+    });
+}
+
+async fn fan_out(db_client: &aws_sdk_dynamodb::Client, http: &HttpClient, policy: RetryPolicy, event: &TaskEvent) -> Result<(), ApiError> {
+    let subscriptions = list_subscriptions(db_client, &event.owner).await?;
+    let mut payload = None;
+
+    for subscription in subscriptions.into_iter().filter(|subscription| subscription.events.contains(&event.change)) {
+        // Built once, so every subscriber sees the same event id
+        let payload = match &payload {
+            Some(payload) => payload,
+            None => payload.insert(payload_of(event)?),
+        };
+
+        let delivery = Delivery::new(&subscription.id, event.change, payload.clone(), None);
+        save_delivery(db_client, &delivery).await?;
+
+        tokio::spawn(deliver(db_client.clone(), http.clone(), policy, subscription, delivery));
+    }
+
+    Ok(())
+}
+
+async fn deliver(db_client: aws_sdk_dynamodb::Client, http: HttpClient, policy: RetryPolicy, subscription: Subscription, mut delivery: Delivery) {
+    loop {
+        let attempt = attempt(&http, policy.timeout, &subscription, &delivery).await;
+        delivery.record(attempt, policy.max_attempts);
+
+        if let Err(err) = save_delivery(&db_client, &delivery).await {
+            tracing::error!(error = %err, subscription = %delivery.subscription, delivery = %delivery.id, "failed to log webhook attempt");
+        }
+
+        match delivery.status {
+            DeliveryStatus::Pending => tokio::time::sleep(policy.backoff(delivery.attempts.len())).await,
+            DeliveryStatus::Succeeded => break,
+            DeliveryStatus::Failed => {
+                tracing::warn!(subscription = %delivery.subscription, delivery = %delivery.id, attempts = delivery.attempts.len(), "webhook delivery failed");
+                break;
+            },
+        }
+    }
+}
+
+/// Signs and sends the delivery once. Only the status code of the response is looked at.
+async fn attempt<C: Connect + Clone + Send + Sync + 'static>(http: &hyper::Client<C>, timeout: Duration, subscription: &Subscription, delivery: &Delivery) -> Attempt {
+    let at = db::now_millis();
+    let started = Instant::now();
+
+    let request = hyper::Request::post(subscription.url.as_str())
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::USER_AGENT, USER_AGENT)
+        .header(EVENT_HEADER, delivery.event.name())
+        .header(DELIVERY_HEADER, delivery.id.as_str())
+        .header(SIGNATURE_HEADER, signature(&subscription.secret, at / 1000, &delivery.payload))
+        .body(hyper::Body::from(delivery.payload.clone()));
+
+    let outcome = match request {
+        Ok(request) => match metrics::observe("webhook", "POST", tokio::time::timeout(timeout, http.request(request))).await {
+            Ok(Ok(response)) => Ok(response.status().as_u16()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err(format!["no response within {:?}", timeout]),
+        },
+        Err(err) => Err(err.to_string()),
+    };
+
+    let (status_code, error) = match outcome {
+        Ok(status) => (Some(status), None),
+        Err(err) => (None, Some(err.chars().take(MAX_ERROR_LENGTH).collect())),
+    };
+
+    Attempt { at, duration_ms: started.elapsed().as_millis() as u64, status_code, error }
+}
+
+pub async fn save_subscription(db_client: &aws_sdk_dynamodb::Client, subscription: &Subscription) -> Result<(), ApiError> {
+    let put = db_client.put_item()
+        .table_name(db::Table::Webhooks.as_str())
+        .set_item(Some(serde_dynamo::to_item(subscription)?))
+        .send();
+
+    metrics::observe("dynamodb", "PutItem", put).await?;
+
+    Ok(())
+}
+
+pub async fn get_subscription(db_client: &aws_sdk_dynamodb::Client, owner: &str, id: &str) -> Result<Subscription, ApiError> {
+    let get = db_client.get_item()
+        .table_name(db::Table::Webhooks.as_str())
+        .key("owner", AttributeValue::S(owner.to_string()))
+        .key("id", AttributeValue::S(id.to_string()))
+        .send();
+
+    match metrics::observe("dynamodb", "GetItem", get).await?.item {
+        Some(item) => Ok(serde_dynamo::from_item(item)?),
+        None => Err(ApiError::ResourceNotFound),
+    }
+}
+
+pub async fn list_subscriptions(db_client: &aws_sdk_dynamodb::Client, owner: &str) -> Result<Vec<Subscription>, ApiError> {
+    let query = db_client.query()
+        .table_name(db::Table::Webhooks.as_str())
+        .key_condition_expression("#owner = :owner")
+        .expression_attribute_names("#owner", "owner")
+        .expression_attribute_values(":owner", AttributeValue::S(owner.to_string()))
+        .send();
+
+    let result = metrics::observe("dynamodb", "Query", query).await?;
+
+    Ok(serde_dynamo::from_items(result.items.unwrap_or_default())?)
+}
+
+pub async fn delete_subscription(db_client: &aws_sdk_dynamodb::Client, owner: &str, id: &str) -> Result<(), ApiError> {
+    let delete = db_client.delete_item()
+        .table_name(db::Table::Webhooks.as_str())
+        .key("owner", AttributeValue::S(owner.to_string()))
+        .key("id", AttributeValue::S(id.to_string()))
+        .condition_expression("attribute_exists(id)")
+        .send();
+
+    match metrics::observe("dynamodb", "DeleteItem", delete).await {
+        Ok(_) => Ok(()),
+        Err(aws_sdk_dynamodb::types::SdkError::ServiceError { err, .. }) if err.is_conditional_check_failed_exception() => Err(ApiError::ResourceNotFound),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn save_delivery(db_client: &aws_sdk_dynamodb::Client, delivery: &Delivery) -> Result<(), ApiError> {
+    let put = db_client.put_item()
+        .table_name(db::Table::WebhookDeliveries.as_str())
+        .set_item(Some(serde_dynamo::to_item(delivery)?))
+        .send();
+
+    metrics::observe("dynamodb", "PutItem", put).await?;
+
+    Ok(())
+}
+
+pub async fn get_delivery(db_client: &aws_sdk_dynamodb::Client, subscription: &str, id: &str) -> Result<Delivery, ApiError> {
+    let get = db_client.get_item()
+        .table_name(db::Table::WebhookDeliveries.as_str())
+        .key("subscription", AttributeValue::S(subscription.to_string()))
+        .key("id", AttributeValue::S(id.to_string()))
+        .send();
+
+    match metrics::observe("dynamodb", "GetItem", get).await?.item {
+        Some(item) => Ok(serde_dynamo::from_item(item)?),
+        None => Err(ApiError::ResourceNotFound),
+    }
+}
+
+/// Newest deliveries first.
+pub async fn list_deliveries(db_client: &aws_sdk_dynamodb::Client, subscription: &str, limit: i32, cursor: Option<&str>) -> Result<DeliveryPage, ApiError> {
+    let mut query = db_client.query()
+        .table_name(db::Table::WebhookDeliveries.as_str())
+        .key_condition_expression("#subscription = :subscription")
+        .expression_attribute_names("#subscription", "subscription")
+        .expression_attribute_values(":subscription", AttributeValue::S(subscription.to_string()))
+        .scan_index_forward(false)
+        .limit(limit);
+
+    if let Some(cursor) = cursor {
+        let start: HashMap<String, AttributeValue> = db::decode_cursor(cursor).ok_or(ApiError::InvalidQueryParameter)?;
+        query = query.set_exclusive_start_key(Some(start));
+    }
+
+    let result = metrics::observe("dynamodb", "Query", query.send()).await?;
+
+    Ok(DeliveryPage {
+        deliveries: serde_dynamo::from_items(result.items.unwrap_or_default())?,
+        next_cursor: result.last_evaluated_key.as_ref().map(db::encode_cursor),
+    })
+}
+
+#[cfg(test)]
+mod webhooks_tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers one request with `status` and hands back what was received, standing in for a receiver.
+    async fn receive_once(listener: &TcpListener, status: u16) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+
+            let text = String::from_utf8_lossy(&request).to_string();
+
+            if let Some(head_end) = text.find("\r\n\r\n") {
+                let length = text.lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|length| length.trim().parse::<usize>().unwrap()))
+                    .unwrap_or(0);
+
+                if request.len() >= head_end + 4 + length {
+                    break;
+                }
+            }
+        }
+
+        stream.write_all(format!["HTTP/1.1 {} Stand-in\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status].as_bytes()).await.unwrap();
+
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn every_attempt_is_signed_and_recorded_until_one_is_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let subscription = Subscription {
+            owner: "nate".to_string(),
+            id: "hook".to_string(),
+            url: format!["http://{}/hook", listener.local_addr().unwrap()],
+            secret: "0123456789abcdef".to_string(),
+            events: vec![TaskChange::Completed],
+            created_at: 0,
+        };
+
+        let event = TaskEvent::new(TaskChange::Completed, "nate", "a", None);
+        let mut delivery = Delivery::new(&subscription.id, event.change, payload_of(&event).unwrap(), None);
+        let http = hyper::Client::new();
+
+        for status in [500, 204] {
+            let (attempt, request) = tokio::join!(
+                attempt(&http, Duration::from_secs(5), &subscription, &delivery),
+                receive_once(&listener, status),
+            );
+
+            let signature_line = request.lines()
+                .find_map(|line| line.strip_prefix("x-nys-signature: "))
+                .unwrap();
+            let timestamp: u64 = signature_line.strip_prefix("t=").and_then(|rest| rest.split(',').next()).unwrap().parse().unwrap();
+
+            assert_eq!(signature_line, signature(&subscription.secret, timestamp, &delivery.payload));
+            assert!(request.ends_with(&delivery.payload));
+            assert_eq!(attempt.status_code, Some(status));
+
+            delivery.record(attempt, 3);
+        }
+
+        assert_eq!(delivery.status, DeliveryStatus::Succeeded);
+        assert_eq!(delivery.attempts.len(), 2);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 6,
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(1),
+        };
+
+        let delays: Vec<u64> = (1..=5).map(|failed| policy.backoff(failed).as_secs()).collect();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+    }
+
+    #[test]
+    fn urls_must_be_absolute_and_https_unless_allowed() {
+        assert!(is_valid_url("https://hooks.example.com/tasks", false));
+        assert!(!is_valid_url("http://hooks.example.com/tasks", false));
+        assert!(is_valid_url("http://localhost:9000/tasks", true));
+        assert!(!is_valid_url("/tasks", true));
+        assert!(!is_valid_url("ftp://hooks.example.com", true));
+        assert!(is_valid_url("https://93.184.215.14/tasks", false));
+        assert!(!is_valid_url("http://169.254.169.254/latest/meta-data", true));
+        assert!(!is_valid_url("https://[::1]:9000/tasks", false));
+    }
+
+    #[test]
+    fn only_public_addresses_receive_webhooks() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public_address(ip.parse().unwrap()), "{} is public", ip);
+        }
+
+        for ip in ["127.0.0.1", "10.1.2.3", "172.20.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{} is not public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn deliveries_never_reach_this_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let http = hyper::Client::builder().build::<_, hyper::Body>(PublicConnector::new());
+
+        for url in [format!["http://127.0.0.1:{}/hook", port], format!["http://localhost:{}/hook", port], format!["http://[::ffff:127.0.0.1]:{}/hook", port]] {
+            let request = hyper::Request::post(url.as_str()).body(hyper::Body::empty()).unwrap();
+            assert!(http.request(request).await.is_err(), "{} was reached", url);
+        }
+    }
+}